@@ -0,0 +1,466 @@
+//! Generation of the Packages and Sources indices of an apt repository.
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+use xz2::{read::XzDecoder, write::XzEncoder};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to read {}", file.display())]
+    Read {
+        file: Box<Path>,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to write {}", file.display())]
+    Write {
+        file: Box<Path>,
+        #[source]
+        source: io::Error,
+    },
+    #[error("{} does not contain a control archive", _0.display())]
+    MissingControlArchive(Box<Path>),
+    #[error("{} does not contain a control file", _0.display())]
+    MissingControl(Box<Path>),
+    #[error("{} uses an unsupported compression for {}", _0.display(), _1)]
+    UnsupportedCompression(Box<Path>, Box<str>),
+    #[error("{} is missing the {} field", _0.display(), _1)]
+    MissingField(Box<Path>, &'static str),
+    #[error("{} references {}, which does not exist", _0.display(), _1)]
+    MissingFile(Box<Path>, Box<str>),
+}
+
+/// The size and hashes of a file, as listed in apt indices.
+#[derive(Debug, Clone)]
+pub struct Checksums {
+    pub size: u64,
+    pub md5: String,
+    pub sha1: String,
+    pub sha256: String,
+    pub sha512: String,
+}
+
+impl Checksums {
+    /// Hashes the contents of a file in a single pass.
+    pub fn of_file(path: &Path) -> Result<Self, Error> {
+        let read_error = |source| Error::Read {
+            file: path.into(),
+            source,
+        };
+
+        let mut file = File::open(path).map_err(read_error)?;
+
+        let mut md5 = Md5::new();
+        let mut sha1 = Sha1::new();
+        let mut sha256 = Sha256::new();
+        let mut sha512 = Sha512::new();
+        let mut size = 0;
+
+        let buffer = &mut [0u8; 16 * 1024];
+        loop {
+            let read = file.read(buffer).map_err(read_error)?;
+            if read == 0 {
+                break;
+            }
+
+            let data = &buffer[..read];
+            md5.input(data);
+            sha1.input(data);
+            sha256.input(data);
+            sha512.input(data);
+            size += read as u64;
+        }
+
+        Ok(Self {
+            size,
+            md5: format!("{:x}", md5.result()),
+            sha1: format!("{:x}", sha1.result()),
+            sha256: format!("{:x}", sha256.result()),
+            sha512: format!("{:x}", sha512.result()),
+        })
+    }
+}
+
+/// Generates the Packages index of an arch from the debs in a pool.
+///
+/// `Filename` fields are relative to the `pocket_dir`, which is the root of the repository.
+pub fn packages(pocket_dir: &Path, pool: &str, arch: &str) -> Result<String, Error> {
+    let mut index = String::new();
+
+    for deb in files_with_extension(&pocket_dir.join(pool), "deb")? {
+        let control = deb_control(&deb)?;
+        let control = control.trim_end();
+
+        let deb_arch = field(control, "Architecture")
+            .ok_or_else(|| Error::MissingField(deb.as_path().into(), "Architecture"))?;
+
+        if deb_arch != arch && deb_arch != "all" {
+            continue;
+        }
+
+        let sums = Checksums::of_file(&deb)?;
+        let filename = deb.strip_prefix(pocket_dir).expect("deb outside of pocket");
+
+        let _ = write!(
+            index,
+            "{}\nFilename: {}\nSize: {}\nMD5sum: {}\nSHA1: {}\nSHA256: {}\nSHA512: {}\n\n",
+            control,
+            filename.display(),
+            sums.size,
+            sums.md5,
+            sums.sha1,
+            sums.sha256,
+            sums.sha512
+        );
+    }
+
+    Ok(index)
+}
+
+const REGENERATED_SOURCE_FIELDS: &[&str] = &[
+    "Source",
+    "Files",
+    "Checksums-Sha1",
+    "Checksums-Sha256",
+    "Checksums-Sha512",
+];
+
+/// Generates the Sources index from the dsc files in a pool.
+///
+/// `Directory` fields are relative to the `pocket_dir`, which is the root of the repository.
+pub fn sources(pocket_dir: &Path, pool: &str) -> Result<String, Error> {
+    let mut index = String::new();
+
+    for dsc in files_with_extension(&pocket_dir.join(pool), "dsc")? {
+        let raw = fs::read_to_string(&dsc).map_err(|source| Error::Read {
+            file: dsc.as_path().into(),
+            source,
+        })?;
+
+        let control = strip_signature(&raw);
+        let source = field(control, "Source")
+            .ok_or_else(|| Error::MissingField(dsc.as_path().into(), "Source"))?;
+        let files = field(control, "Files")
+            .ok_or_else(|| Error::MissingField(dsc.as_path().into(), "Files"))?;
+
+        let directory = dsc.parent().expect("dsc without a parent");
+
+        // The dsc itself is listed alongside the files that it references.
+        let mut entries = vec![(
+            dsc.file_name().unwrap().to_str().unwrap().to_owned(),
+            Checksums::of_file(&dsc)?,
+        )];
+
+        for line in files.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let name = line
+                .split_whitespace()
+                .nth(2)
+                .ok_or_else(|| Error::MissingField(dsc.as_path().into(), "Files"))?;
+
+            let path = directory.join(name);
+            if !path.exists() {
+                return Err(Error::MissingFile(dsc.as_path().into(), name.into()));
+            }
+
+            entries.push((name.to_owned(), Checksums::of_file(&path)?));
+        }
+
+        let _ = writeln!(index, "Package: {}", source);
+
+        // Checksum fields are regenerated below, to include the dsc and every hash kind.
+        for (name, value) in fields(control) {
+            if !REGENERATED_SOURCE_FIELDS.contains(&name) {
+                let _ = writeln!(index, "{}:{}", name, value);
+            }
+        }
+
+        let directory = directory
+            .strip_prefix(pocket_dir)
+            .expect("dsc outside of pocket");
+        let _ = writeln!(index, "Directory: {}", directory.display());
+
+        checksum_list(&mut index, "Files", &entries, |sums| sums.md5.as_str());
+        checksum_list(&mut index, "Checksums-Sha1", &entries, |sums| {
+            sums.sha1.as_str()
+        });
+        checksum_list(&mut index, "Checksums-Sha256", &entries, |sums| {
+            sums.sha256.as_str()
+        });
+        checksum_list(&mut index, "Checksums-Sha512", &entries, |sums| {
+            sums.sha512.as_str()
+        });
+
+        index.push('\n');
+    }
+
+    Ok(index)
+}
+
+/// Writes an index, along with gzip and xz compressed copies of it.
+pub fn write_compressed(path: &Path, index: &[u8]) -> Result<(), Error> {
+    write_file(path, |mut file| file.write_all(index))?;
+
+    write_file(&append_extension(path, "gz"), |file| {
+        let mut encoder = GzEncoder::new(file, Compression::best());
+        encoder.write_all(index)?;
+        encoder.finish().map(|_| ())
+    })?;
+
+    write_file(&append_extension(path, "xz"), |file| {
+        let mut encoder = XzEncoder::new(file, 9);
+        encoder.write_all(index)?;
+        encoder.finish().map(|_| ())
+    })
+}
+
+fn write_file<F: FnOnce(File) -> io::Result<()>>(file: &Path, writer: F) -> Result<(), Error> {
+    File::create(file)
+        .and_then(writer)
+        .map_err(|source| Error::Write {
+            file: file.into(),
+            source,
+        })
+}
+
+/// Fetches the value of a field in a deb822 paragraph, with continuation lines included.
+pub fn field<'a>(paragraph: &'a str, key: &str) -> Option<&'a str> {
+    fields(paragraph)
+        .find(|&(name, _)| name == key)
+        .map(|(_, value)| value.trim())
+}
+
+/// Iterates the fields of a deb822 paragraph as names and their untrimmed values.
+fn fields(paragraph: &str) -> impl Iterator<Item = (&str, &str)> {
+    let mut offsets = Vec::new();
+    let mut position = 0;
+
+    for line in paragraph.split('\n') {
+        if !line.starts_with(' ') && !line.starts_with('\t') && line.contains(':') {
+            offsets.push(position);
+        }
+
+        position += line.len() + 1;
+    }
+
+    offsets.push(paragraph.len());
+
+    (0..offsets.len() - 1).map(move |id| {
+        let field = paragraph[offsets[id]..offsets[id + 1]].trim_end();
+        let colon = field.find(':').unwrap();
+        (&field[..colon], &field[colon + 1..])
+    })
+}
+
+fn checksum_list<F: Fn(&Checksums) -> &str>(
+    index: &mut String,
+    name: &str,
+    entries: &[(String, Checksums)],
+    hash: F,
+) {
+    let _ = writeln!(index, "{}:", name);
+    for (file, sums) in entries {
+        let _ = writeln!(index, " {} {} {}", hash(sums), sums.size, file);
+    }
+}
+
+/// Extracts the control file from the control archive of a deb.
+fn deb_control(deb: &Path) -> Result<String, Error> {
+    let read_error = |source| Error::Read {
+        file: deb.into(),
+        source,
+    };
+
+    let mut archive = ar::Archive::new(File::open(deb).map_err(read_error)?);
+
+    while let Some(entry) = archive.next_entry() {
+        let entry = entry.map_err(read_error)?;
+        let identifier = String::from_utf8_lossy(entry.header().identifier()).into_owned();
+
+        if !identifier.starts_with("control.tar") {
+            continue;
+        }
+
+        let reader: Box<dyn Read + '_> = match &identifier["control.tar".len()..] {
+            "" => Box::new(entry),
+            ".gz" => Box::new(GzDecoder::new(entry)),
+            ".xz" => Box::new(XzDecoder::new(entry)),
+            other => return Err(Error::UnsupportedCompression(deb.into(), other.into())),
+        };
+
+        let mut tar = tar::Archive::new(reader);
+        for file in tar.entries().map_err(read_error)? {
+            let mut file = file.map_err(read_error)?;
+
+            let is_control = file
+                .path()
+                .map(|path| path == Path::new("./control") || path == Path::new("control"))
+                .unwrap_or(false);
+
+            if is_control {
+                let mut control = String::new();
+                file.read_to_string(&mut control).map_err(read_error)?;
+                return Ok(control);
+            }
+        }
+
+        return Err(Error::MissingControl(deb.into()));
+    }
+
+    Err(Error::MissingControlArchive(deb.into()))
+}
+
+/// Recursively collects files in a directory with the given extension, in sorted order.
+fn files_with_extension(dir: &Path, extension: &str) -> Result<Vec<PathBuf>, Error> {
+    let mut files = walk(dir)?;
+    files.retain(|path| path.extension().map_or(false, |ext| ext == extension));
+    Ok(files)
+}
+
+/// Recursively collects every file in a directory, in sorted order.
+pub(crate) fn walk(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut found = Vec::new();
+
+    if !dir.exists() {
+        return Ok(found);
+    }
+
+    let mut queue = vec![dir.to_path_buf()];
+    while let Some(dir) = queue.pop() {
+        let read_error = |source| Error::Read {
+            file: dir.as_path().into(),
+            source,
+        };
+
+        for entry in fs::read_dir(&dir).map_err(read_error)? {
+            let path = entry.map_err(read_error)?.path();
+            if path.is_dir() {
+                queue.push(path);
+            } else {
+                found.push(path);
+            }
+        }
+    }
+
+    found.sort();
+    Ok(found)
+}
+
+/// Removes the OpenPGP armor of a signed dsc file, if it is signed.
+fn strip_signature(dsc: &str) -> &str {
+    const HEADER: &str = "-----BEGIN PGP SIGNED MESSAGE-----";
+    const SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----";
+
+    if !dsc.starts_with(HEADER) {
+        return dsc.trim();
+    }
+
+    // The armor headers are terminated by the first empty line.
+    let start = dsc.find("\n\n").map_or(dsc.len(), |pos| pos + 2);
+    let end = dsc.find(SIGNATURE).unwrap_or_else(|| dsc.len());
+
+    dsc[start..end.max(start)].trim()
+}
+
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DSC: &str = "Format: 3.0 (native)
+Source: hello
+Binary: hello, hello-doc
+Architecture: any all
+Version: 1.0.0~1570000000~19.10~abcdef0
+Maintainer: CI <ci@example.com>
+Build-Depends: debhelper (>= 9),
+ cargo
+Package-List:
+ hello deb utils optional arch=any
+ hello-doc deb doc optional arch=all
+Files:
+ d41d8cd98f00b204e9800998ecf8427e 1024 hello_1.0.0.tar.xz
+";
+
+    #[test]
+    fn parses_fields() {
+        let names = fields(DSC).map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "Format",
+                "Source",
+                "Binary",
+                "Architecture",
+                "Version",
+                "Maintainer",
+                "Build-Depends",
+                "Package-List",
+                "Files"
+            ]
+        );
+
+        // Values are untrimmed, and include their continuation lines.
+        let (_, depends) = fields(DSC).nth(6).unwrap();
+        assert_eq!(depends, " debhelper (>= 9),\n cargo");
+    }
+
+    #[test]
+    fn finds_fields() {
+        assert_eq!(field(DSC, "Source"), Some("hello"));
+        assert_eq!(field(DSC, "Format"), Some("3.0 (native)"));
+        assert_eq!(
+            field(DSC, "Package-List"),
+            Some("hello deb utils optional arch=any\n hello-doc deb doc optional arch=all")
+        );
+        assert_eq!(
+            field(DSC, "Files"),
+            Some("d41d8cd98f00b204e9800998ecf8427e 1024 hello_1.0.0.tar.xz")
+        );
+
+        // Names are matched exactly, rather than as prefixes of other fields.
+        assert_eq!(field(DSC, "Binary"), Some("hello, hello-doc"));
+        assert_eq!(field(DSC, "Build"), None);
+        assert_eq!(field(DSC, "Checksums-Sha256"), None);
+        assert_eq!(field("", "Source"), None);
+    }
+
+    #[test]
+    fn strips_signatures() {
+        let signed = [
+            "-----BEGIN PGP SIGNED MESSAGE-----\n",
+            "Hash: SHA512\n",
+            "\n",
+            DSC,
+            "\n",
+            "-----BEGIN PGP SIGNATURE-----\n",
+            "\n",
+            "iQIzBAEBCgAdFiEE\n",
+            "-----END PGP SIGNATURE-----\n",
+        ]
+        .concat();
+
+        assert_eq!(strip_signature(&signed), DSC.trim());
+        assert_eq!(field(strip_signature(&signed), "Source"), Some("hello"));
+
+        // Unsigned files are only trimmed.
+        assert_eq!(strip_signature(DSC), DSC.trim());
+
+        // A truncated signature leaves everything after the armor headers.
+        let truncated = "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA512\n\nSource: hello\n";
+        assert_eq!(strip_signature(truncated), "Source: hello");
+    }
+}
@@ -1,106 +1,354 @@
+pub mod index;
 pub mod release;
 
 use crate::{
     config::Config,
-    misc::{check_call, check_output},
+    errors::{DirError, FileError},
+    misc::{check_call, create_and_write},
 };
 
+use itertools::Itertools;
+use std::{collections::HashSet, io, path::Path};
 use tokio::fs;
 
-pub async fn create_dist(config: &Config, pocket: &str, codename: &str, version: &str) -> io::Result<()> {
-    let pocket_dir = config.dirs.pocket.join(pocket);
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to set up the apt repository directories")]
+    Directory(#[from] DirError),
+    #[error("failed to add {} to the pool", file.display())]
+    Pool {
+        file: Box<Path>,
+        #[source]
+        source: io::Error,
+    },
+    #[error("failed to read {}", _0.display())]
+    ReadDir(Box<Path>, #[source] io::Error),
+    #[error("failed to generate the Sources index")]
+    Sources(#[source] index::Error),
+    #[error("failed to generate the Packages index for {}", _0)]
+    Packages(Box<str>, #[source] index::Error),
+    #[error("failed to generate the dist Release file")]
+    Release(#[source] index::Error),
+    #[error("failed to write a release file")]
+    Write(#[from] FileError),
+    #[error("failed to sign {}", _0.display())]
+    Sign(Box<Path>, #[source] io::Error),
+}
+
+/// Replaces the source and binary packages of a package in the pool of a pocket's codename.
+///
+/// Binaries of archs which were not built this time, because they were filtered out or failed
+/// to build, are kept from the packages that are replaced. The new packages are gathered
+/// beside the pool of the package, and swapped in once complete.
+pub async fn pool(
+    config: &Config,
+    pocket: &str,
+    codename: &str,
+    package: &str,
+    files: &[Box<Path>],
+) -> Result<(), Error> {
+    let codename_dir = config.dirs.repo.join(pocket).join("pool").join(codename);
+    let pool_dir = codename_dir.join(package);
+    let staging_dir = codename_dir.join([".", package, ".new"].concat());
+
+    if staging_dir.exists() {
+        remove_dir(&staging_dir).await?;
+    }
+
+    create_dir(&staging_dir).await?;
+
+    for file in files {
+        link(file, &staging_dir).await?;
+    }
+
+    if pool_dir.exists() {
+        let built = files
+            .iter()
+            .filter_map(|file| deb_arch(file))
+            .collect::<HashSet<_>>();
+
+        for file in files_in(&pool_dir)? {
+            match deb_arch(&file) {
+                Some(arch) if !built.contains(arch) => link(&file, &staging_dir).await?,
+                _ => (),
+            }
+        }
+
+        remove_dir(&pool_dir).await?;
+    }
+
+    fs::rename(&staging_dir, &pool_dir)
+        .await
+        .map_err(|source| Error::Pool {
+            file: pool_dir.as_path().into(),
+            source,
+        })
+}
+
+/// Removes a package from the pool of each pocket's codename that `remove` selects, returning
+/// the pockets and codenames that it was removed from.
+pub async fn unpool<F: Fn(&str, &str) -> bool>(
+    config: &Config,
+    package: &str,
+    remove: F,
+) -> Result<Vec<(Box<str>, Box<str>)>, Error> {
+    let mut removed = Vec::new();
+
+    for pocket in subdirectories(&config.dirs.repo)? {
+        let pool_dir = config.dirs.repo.join(&*pocket).join("pool");
+
+        for codename in subdirectories(&pool_dir)? {
+            let package_dir = pool_dir.join(&*codename).join(package);
+            if !package_dir.exists() || !remove(&pocket, &codename) {
+                continue;
+            }
+
+            remove_dir(&package_dir).await?;
+            removed.push((pocket.clone(), codename));
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Removes the pockets which no longer have any packages in their pool, such as the pockets of
+/// closed pull requests, returning the pockets that were removed.
+pub async fn remove_empty_pockets(config: &Config) -> Result<Vec<Box<str>>, Error> {
+    let mut removed = Vec::new();
+
+    'pockets: for pocket in subdirectories(&config.dirs.repo)? {
+        let pocket_dir = config.dirs.repo.join(&*pocket);
+        let pool_dir = pocket_dir.join("pool");
+
+        for codename in subdirectories(&pool_dir)? {
+            if !subdirectories(&pool_dir.join(&*codename))?.is_empty() {
+                continue 'pockets;
+            }
+        }
+
+        fs::remove_dir_all(&pocket_dir)
+            .await
+            .map_err(|source| DirError::Remove {
+                dir: pocket_dir.as_path().into(),
+                source,
+            })?;
+
+        removed.push(pocket);
+    }
+
+    Ok(removed)
+}
+
+/// Generates the `dists/<codename>` directory of a pocket from the contents of its pool.
+pub async fn create_dist(
+    config: &Config,
+    pocket: &str,
+    codename: &str,
+    version: &str,
+) -> Result<(), Error> {
+    let pocket_dir = config.dirs.repo.join(pocket);
     let dist_dir = pocket_dir.join("dists").join(codename);
-    let dist_release = dist_dir.join("Release");
     let comp_dir = dist_dir.join("main");
     let source_dir = comp_dir.join("source");
-    let sources_file = source_dir.join("Sources");
-    let sources_release = source_dir.join("Release");
     let context = &config.context.replace("/", "-");
     let description = &config.description;
 
     let pool = ["pool/", codename].concat();
 
-    fs::create_dir_all(source_dir).await?;
+    create_dir(&source_dir).await?;
 
-    let source = generate_source_directory(&pool, &pocket_dir).await?;
+    let info = release::ReleaseInfo {
+        context,
+        description,
+        pocket,
+        codename,
+        version,
+    };
 
-    fs::write(&sources_file, source).await?;
-
-    check_call("gzip", &["--keep", sources_file.to_str().unwrap()], None).await?;
-
-    release::generate(&sources_releases, "source", context, description, pocket, codename, version).await?;
+    {
+        let (pocket_dir, pool, path) =
+            (pocket_dir.clone(), pool.clone(), source_dir.join("Sources"));
+        blocking(move || {
+            let sources = index::sources(&pocket_dir, &pool)?;
+            index::write_compressed(&path, sources.as_bytes())
+        })
+        .await
+        .map_err(Error::Sources)?;
+    }
 
-    let mut binary_file = fs::OpenOptions::new().append(true).open(&binary_packages).await?;
+    release::generate(&source_dir.join("Release"), "source", &info).await?;
 
     for build_arch in config.archs.keys() {
         let binary_dir = comp_dir.join(&["binary-", build_arch].concat());
-        let binary_packages = binary_dir.join("Packages");
-        let binary_release = binary_dir.join("Release");
 
-        fs::create_dir(binary_dir).await?;
+        create_dir(&binary_dir).await?;
+
+        let (pocket_dir, pool, arch) = (pocket_dir.clone(), pool.clone(), build_arch.clone());
+        let path = binary_dir.join("Packages");
+        blocking(move || {
+            let packages = index::packages(&pocket_dir, &pool, &arch)?;
+            index::write_compressed(&path, packages.as_bytes())
+        })
+        .await
+        .map_err(|why| Error::Packages(build_arch.clone(), why))?;
 
-        let packages = generate_binary_directory(build_arch, &pool, &pocket_dir).await?;
+        release::generate(&binary_dir.join("Release"), build_arch, &info).await?;
+    }
+
+    let build_archs = config.archs.keys().join(" ");
+    let indices = {
+        let dist_dir = dist_dir.clone();
+        blocking(move || release::indices(&dist_dir))
+            .await
+            .map_err(Error::Release)?
+    };
 
-        fs::write(&binary_packages, packages).await?;
+    let release = release::dist(&indices, &build_archs, &info);
+    create_and_write(&dist_dir.join("Release"), release.as_bytes()).await?;
 
-        check_call("gzip", &["--keep", binary_packages.to_str().unwrap()], None).await?;
+    gpg_inrelease(&dist_dir, &config.email)
+        .await
+        .map_err(|why| Error::Sign(dist_dir.join("InRelease").into(), why))?;
+    gpg_release(&dist_dir, &config.email)
+        .await
+        .map_err(|why| Error::Sign(dist_dir.join("Release.gpg").into(), why))?;
 
-        release::generate(&binary_release, build_arch, context, description, pocket, codename, version).await?;
+    Ok(())
+}
+
+/// Runs index work, which reads and hashes every package of a pool, on the blocking thread
+/// pool.
+async fn blocking<T, F>(func: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio_executor::blocking::run(func).await
+}
+
+/// Links a file into a directory, or copies it if it cannot be linked.
+async fn link(file: &Path, dir: &Path) -> Result<(), Error> {
+    let destination = dir.join(file.file_name().expect("pool file without a name"));
+
+    if destination.exists() {
+        return Ok(());
     }
 
-    let build_archs = config.archs.keys().join(" ");
-    let release = dist_release(dist_dir.to_str().unwrap(), &build_archs, context, description, pocket, codename).await?;
+    // Hard links avoid copying large packages, but the build directory may be on another device.
+    if fs::hard_link(file, &destination).await.is_err() {
+        fs::copy(file, &destination)
+            .await
+            .map_err(|source| Error::Pool {
+                file: file.into(),
+                source,
+            })?;
+    }
 
-    fs::write(&dist_release, release).await?;
+    Ok(())
+}
 
-    let dist_dir = dist_dir.to_str().unwrap();
-    gpg_inrelease(dist_dir, &config.email).await?;
-    gpg_release(dist_dir, &config.email).await?;
+/// The arch of a binary package, from its `<name>_<version>_<arch>.deb` file name.
+fn deb_arch(file: &Path) -> Option<&str> {
+    let name = file.file_name()?.to_str()?;
+
+    if !name.ends_with(".deb") {
+        return None;
+    }
+
+    name[..name.len() - ".deb".len()].rsplit('_').next()
 }
 
-async fn generate_source_directory(pool: &str, pocket_dir: &Path) -> io::Result<String> {
-    check_output("apt-ftparchive", &["-qq", "sources", pool], Some(pocket_dir)).await
+async fn create_dir(dir: &Path) -> Result<(), DirError> {
+    fs::create_dir_all(dir)
+        .await
+        .map_err(|source| DirError::Create {
+            dir: dir.into(),
+            source,
+        })
 }
 
-async fn generate_binary_directory(build_arch: &str, pool: &str, pocket_dir: &Path) -> io::Result<String> {
-    check_output("apt-ftparchive", &[
-        "--arch", build_arch,
-        "packages", pool,
-    ], Some(pocket_dir)).await
+async fn gpg_inrelease(dist_dir: &Path, email: &str) -> io::Result<()> {
+    check_call(
+        "gpg",
+        &[
+            "--clearsign",
+            "--local-user",
+            email,
+            "--batch",
+            "--yes",
+            "--digest-algo",
+            "sha512",
+            "-o",
+            "InRelease",
+            "Release",
+        ],
+        Some(dist_dir),
+    )
+    .await
 }
 
-async fn dist_release(dist_dir: &Path, build_archs: &str, context: &str, description: &str, pocket: &str, codename: &str, version: &str) -> io::Result<String> {
-    check_output("apt-ftparchive", &[
-        "-o", &["APT::FTPArchive::Release::Origin=", context, "-", pocket].concat(),
-        "-o", &["APT::FTPArchive::Release::Label=", description, " ", pocket].concat(),
-        "-o", &["APT::FTPArchive::Release::Suite=", codename].concat(),
-        "-o", &["APT::FTPArchive::Release::Version=", version].concat(),
-        "-o", &["APT::FTPArchive::Release::Codename=", codename].concat(),
-        "-o", &["APT::FTPArchive::Release::Architectures=", build_archs].concat(),
-        "-o", "APT::FTPArchive::Release::Components=main",
-        "-o", &["APT::FTPArchive::Release::Description=Pop!_OS Staging ", codename, " ", version, " ", pocket].concat()
-        "release", "."
-    ], Some(dist_dir)).await
+async fn gpg_release(dist_dir: &Path, email: &str) -> io::Result<()> {
+    check_call(
+        "gpg",
+        &[
+            "-abs",
+            "--local-user",
+            email,
+            "--batch",
+            "--yes",
+            "--digest-algo",
+            "sha512",
+            "-o",
+            "Release.gpg",
+            "Release",
+        ],
+        Some(dist_dir),
+    )
+    .await
 }
 
-async fn gpg_inrelease(dist_dir: &str, email: &str) -> io::Result<()> {
-    check_call("gpg", &[
-        "--clearsign",
-        "--local-user", email,
-        "--batch", "--yes",
-        "--digest-algo", "sha512",
-        "-o", &[dist_dir, "/InRelease"].concat(),
-        &[dist_dir, "/Release"].concat(),
-    ], None).await
+async fn remove_dir(dir: &Path) -> Result<(), DirError> {
+    fs::remove_dir_all(dir)
+        .await
+        .map_err(|source| DirError::Remove {
+            dir: dir.into(),
+            source,
+        })
+}
+
+/// Paths of the files within a directory.
+fn files_in(dir: &Path) -> Result<Vec<Box<Path>>, Error> {
+    let read_error = |source| Error::ReadDir(dir.into(), source);
+
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        if path.is_file() {
+            found.push(path.into());
+        }
+    }
+
+    Ok(found)
 }
 
-async fn gpg_release(dist_dir: &str, email: &str) -> io::Result<()> {
-    check_call("gpg", &[
-        "-abs",
-        "--local-user", email,
-        "--batch", "--yes",
-        "--digest-algo", "sha512",
-        "-o", &[dist_dir, "/Release.gpg"].concat(),
-        &[dist_dir, "/Release"].concat(),
-    ], None).await
+/// Names of the directories within a directory, which has none if it does not exist.
+fn subdirectories(dir: &Path) -> Result<Vec<Box<str>>, Error> {
+    let mut found = Vec::new();
+
+    if !dir.exists() {
+        return Ok(found);
+    }
+
+    let read_error = |source| Error::ReadDir(dir.into(), source);
+
+    for entry in std::fs::read_dir(dir).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        if path.is_dir() {
+            if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+                found.push(name.into());
+            }
+        }
+    }
+
+    Ok(found)
 }
@@ -1,21 +1,201 @@
-use crate::{errors::Error, misc::create_and_write, Series};
+use super::index;
+use crate::{errors::FileError, misc::create_and_write};
+use chrono::Utc;
 use markup::raw;
-use std::path::Path;
+use std::{fmt::Write, path::Path};
+
+/// Describes the pocket and codename that a dist is being generated for.
+#[derive(Debug, Clone, Copy)]
+pub struct ReleaseInfo<'a> {
+    pub context: &'a str,
+    pub description: &'a str,
+    pub pocket: &'a str,
+    pub codename: &'a str,
+    pub version: &'a str,
+}
 
 markup::define! {
-    ReleaseFileTemplate<'a>(arch: &str, context: &'a str, description: &'a str, codename: &'a str, version: &'a str, pocket: &'a str) {
-        "Archive: " { raw(codename) } "\n"
-        "Version: " { raw(version) } "\n"
+    ReleaseFileTemplate<'a>(arch: &'a str, info: &'a ReleaseInfo<'a>) {
+        "Archive: " { raw(info.codename) } "\n"
+        "Version: " { raw(info.version) } "\n"
         "Component: main\n"
-        "Origin: " { raw(context)} "-" { raw(pocket)} "\n"
-        "Label: " { raw(description) } " " { raw(pocket)} "\n"
+        "Origin: " { raw(info.context) } "-" { raw(info.pocket) } "\n"
+        "Label: " { raw(info.description) } " " { raw(info.pocket) } "\n"
         "Architecture: " { raw(arch) } "\n"
     }
 }
 
-pub async fn generate(file: &Path, arch: &str, context: &str, description: &str, pocket: &str, codename: &str, version: &str) -> Result<(), Error> {
-    create_and_write(file, format!("{}", ReleaseFileTemplate { arch, context, description, codename, version, pocket }).as_bytes())
-        .await?;
+/// Writes the Release file of a component's source or binary directory.
+pub async fn generate(file: &Path, arch: &str, info: &ReleaseInfo<'_>) -> Result<(), FileError> {
+    let template = ReleaseFileTemplate { arch, info };
+    create_and_write(file, template.to_string().as_bytes()).await
+}
+
+/// Hashes every index beneath a dist, by its path relative to the dist.
+pub fn indices(dist_dir: &Path) -> Result<Vec<(String, index::Checksums)>, index::Error> {
+    let mut entries = Vec::new();
+    for path in index::walk(&dist_dir.join("main"))? {
+        let name = path.strip_prefix(dist_dir).expect("index outside of dist");
+        entries.push((
+            name.display().to_string(),
+            index::Checksums::of_file(&path)?,
+        ));
+    }
+
+    Ok(entries)
+}
+
+/// Generates the Release file of a dist, with the hashes of its indices.
+pub fn dist(entries: &[(String, index::Checksums)], archs: &str, info: &ReleaseInfo<'_>) -> String {
+    let mut release = String::new();
+
+    let _ = writeln!(release, "Origin: {}-{}", info.context, info.pocket);
+    let _ = writeln!(release, "Label: {} {}", info.description, info.pocket);
+    let _ = writeln!(release, "Suite: {}", info.codename);
+    let _ = writeln!(release, "Version: {}", info.version);
+    let _ = writeln!(release, "Codename: {}", info.codename);
+    let _ = writeln!(
+        release,
+        "Date: {}",
+        Utc::now().format("%a, %d %b %Y %H:%M:%S UTC")
+    );
+    let _ = writeln!(release, "Architectures: {}", archs);
+    let _ = writeln!(release, "Components: main");
+    let _ = writeln!(
+        release,
+        "Description: {} {} {} {}",
+        info.description, info.codename, info.version, info.pocket
+    );
+
+    let hashes: [(&str, fn(&index::Checksums) -> &str); 4] = [
+        ("MD5Sum", |sums| sums.md5.as_str()),
+        ("SHA1", |sums| sums.sha1.as_str()),
+        ("SHA256", |sums| sums.sha256.as_str()),
+        ("SHA512", |sums| sums.sha512.as_str()),
+    ];
+
+    for &(field, hash) in &hashes {
+        let _ = writeln!(release, "{}:", field);
+        for (name, sums) in entries {
+            let _ = writeln!(release, " {} {:>16} {}", hash(sums), sums.size, name);
+        }
+    }
+
+    release
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksums(size: u64, digit: &str) -> index::Checksums {
+        index::Checksums {
+            size,
+            md5: digit.repeat(32),
+            sha1: digit.repeat(40),
+            sha256: digit.repeat(64),
+            sha512: digit.repeat(128),
+        }
+    }
+
+    #[test]
+    fn generates_dist_release() {
+        let info = ReleaseInfo {
+            context: "pop-os-staging",
+            description: "Pop!_OS Staging",
+            pocket: "master",
+            codename: "eoan",
+            version: "19.10",
+        };
+
+        let entries = [
+            (
+                "main/binary-amd64/Packages".to_owned(),
+                checksums(1024, "a"),
+            ),
+            ("main/source/Sources".to_owned(), checksums(12, "b")),
+        ];
+
+        let release = dist(&entries, "amd64 i386", &info);
+
+        let mut expected = vec![
+            "Origin: pop-os-staging-master",
+            "Label: Pop!_OS Staging master",
+            "Suite: eoan",
+            "Version: 19.10",
+            "Codename: eoan",
+            "Architectures: amd64 i386",
+            "Components: main",
+            "Description: Pop!_OS Staging eoan 19.10 master",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
 
-    Ok(())
+        // Sizes are right-aligned, so that the names line up.
+        for &(field, len) in &[
+            ("MD5Sum", 32),
+            ("SHA1", 40),
+            ("SHA256", 64),
+            ("SHA512", 128),
+        ] {
+            expected.push([field, ":"].concat());
+            expected.push(
+                [
+                    " ",
+                    &"a".repeat(len),
+                    "             1024 main/binary-amd64/Packages",
+                ]
+                .concat(),
+            );
+            expected.push(
+                [
+                    " ",
+                    &"b".repeat(len),
+                    "               12 main/source/Sources",
+                ]
+                .concat(),
+            );
+        }
+
+        // The date is the time of generation, so is only checked for its presence.
+        let lines = release
+            .lines()
+            .filter(|line| !line.starts_with("Date: "))
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines, expected);
+        assert_eq!(
+            release.lines().nth(5).map(|line| &line[..6]),
+            Some("Date: ")
+        );
+        assert!(release.ends_with('\n'));
+    }
+
+    #[test]
+    fn generates_component_release() {
+        let info = ReleaseInfo {
+            context: "pop-os-staging",
+            description: "Pop!_OS Staging",
+            pocket: "master",
+            codename: "eoan",
+            version: "19.10",
+        };
+
+        let release = ReleaseFileTemplate {
+            arch: "amd64",
+            info: &info,
+        }
+        .to_string();
+
+        assert_eq!(
+            release,
+            "Archive: eoan\n\
+             Version: 19.10\n\
+             Component: main\n\
+             Origin: pop-os-staging-master\n\
+             Label: Pop!_OS Staging master\n\
+             Architecture: amd64\n"
+        );
+    }
 }
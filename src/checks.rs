@@ -0,0 +1,153 @@
+//! Reports the outcome of build stages as GitHub check runs, with build logs attached.
+
+use crate::github::{self, auth, CheckAnnotation, CheckOutput, CheckRun};
+use reqwest::Client;
+
+/// The API rejects requests with more than this number of annotations.
+const MAX_ANNOTATIONS: usize = 50;
+
+/// The number of trailing log lines to attach to the check run.
+const TAIL_LINES: usize = 100;
+
+/// The API limits the text of a check run to 65535 characters.
+const MAX_TEXT: usize = 60_000;
+
+/// Prefix that sbuild and debuild substitute for the package build directory.
+const BUILD_DIR_PREFIX: &str = "/<<PKGBUILDDIR>>/";
+
+/// Creates a check run for a build stage of a commit.
+///
+/// `failure` is the error that the stage failed with, which contains its build log.
+/// Failing to create the check run is logged, but is never treated as a build failure.
+pub async fn report(
+    client: &Client,
+    owner: &str,
+    repo: &str,
+    sha: &str,
+    name: &str,
+    details_url: Option<&str>,
+    failure: Option<&anyhow::Error>,
+) {
+    let log = failure.map(|why| {
+        let log = why
+            .chain()
+            .map(|cause| cause.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        auth::redact(&log).into_owned()
+    });
+
+    let (conclusion, title, annotations, text) = match log.as_ref() {
+        Some(log) => (
+            "failure",
+            "Build failed",
+            annotations(log),
+            Some(["```\n", tail(log), "\n```"].concat()),
+        ),
+        None => ("success", "Build succeeded", Vec::new(), None),
+    };
+
+    let summary = [name, ": ", title].concat();
+
+    let check_run = CheckRun {
+        name,
+        head_sha: sha,
+        status: "completed",
+        conclusion,
+        details_url,
+        output: CheckOutput {
+            title,
+            summary: &summary,
+            text: text.as_ref().map(|text| text.as_str()),
+            annotations: &annotations,
+        },
+    };
+
+    if let Err(why) = github::check_run(client, owner, repo, &check_run).await {
+        warn!(
+            "{} commit {}: failed to create {} check run: {}",
+            repo, sha, name, why
+        );
+    }
+}
+
+/// Fetches the last lines of a build log, within the size limit of a check run.
+pub fn tail(log: &str) -> &str {
+    let mut start = log
+        .rmatch_indices('\n')
+        .nth(TAIL_LINES)
+        .map_or(0, |(pos, _)| pos + 1);
+
+    if log.len() - start > MAX_TEXT {
+        start = log.len() - MAX_TEXT;
+        while !log.is_char_boundary(start) {
+            start += 1;
+        }
+    }
+
+    &log[start..]
+}
+
+/// Locates compiler diagnostics in a build log which point at files in the repository.
+///
+/// Recognizes the `path:line:column: error: message` form of GCC and Clang, and the
+/// `--> path:line:column` form that rustc emits after an `error` line.
+pub fn annotations(log: &str) -> Vec<CheckAnnotation> {
+    let mut annotations = Vec::new();
+    let mut rust_error: Option<&str> = None;
+
+    for line in log.lines() {
+        if annotations.len() == MAX_ANNOTATIONS {
+            break;
+        }
+
+        let trimmed = line.trim();
+
+        if trimmed.starts_with("error") {
+            rust_error = Some(trimmed);
+        }
+
+        if let Some(pos) = trimmed.find(": error: ") {
+            if let Some((path, line)) = file_location(&trimmed[..pos]) {
+                annotations.push(annotation(path, line, &trimmed[pos + 2..]));
+            }
+        } else if trimmed.starts_with("--> ") {
+            if let Some(message) = rust_error.take() {
+                if let Some((path, line)) = file_location(&trimmed[4..]) {
+                    annotations.push(annotation(path, line, message));
+                }
+            }
+        }
+    }
+
+    annotations
+}
+
+fn annotation(path: &str, line: u32, message: &str) -> CheckAnnotation {
+    CheckAnnotation {
+        path: path.into(),
+        start_line: line,
+        end_line: line,
+        annotation_level: "failure",
+        message: message.into(),
+    }
+}
+
+/// Parses the path and line from a `path:line` or `path:line:column` location.
+fn file_location(location: &str) -> Option<(&str, u32)> {
+    let mut fields = location.split(':');
+    let path = fields.next()?;
+    let line = fields.next()?.parse::<u32>().ok()?;
+
+    // Paths in logs are relative to the build directory, which is the root of the repository.
+    let path = if path.starts_with(BUILD_DIR_PREFIX) {
+        &path[BUILD_DIR_PREFIX.len()..]
+    } else if path.starts_with('/') || path.is_empty() {
+        return None;
+    } else {
+        path
+    };
+
+    Some((path, line))
+}
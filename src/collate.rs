@@ -1,17 +1,46 @@
-use crate::{config::Config, fetcher::Repository, git::GitTar, STRING_BUF};
+use crate::{
+    config::{BranchTarget, Config},
+    errors::format_error,
+    fetcher::Repository,
+    filter::Filters,
+    git::GitTar,
+    state::{BuildKey, Stage, State, Status},
+    STRING_BUF,
+};
 
 use futures::{prelude::*, stream::FuturesUnordered};
+use reqwest::Client;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+/// A commit which is to be built for a pocket.
+#[derive(Debug, Clone)]
+pub struct Build {
+    pub git: GitTar,
+    /// Built with the version of its changelog, rather than a snapshot version.
+    pub release: bool,
+}
 
 /// Collates the build queue, and all of its required information.
+///
+/// Commits which fail to archive are recorded as failed source stages, and left out.
 pub async fn build_queue<'a>(
     config: &'a Config,
+    client: &Client,
+    state: &State,
+    filters: &Filters,
     repo: &'a Repository,
-) -> HashMap<&'a str, HashMap<&'a str, GitTar>> {
-    let mut build_queue = HashMap::<&'a str, HashMap<&'a str, GitTar>>::new();
+) -> HashMap<&'a str, HashMap<Box<str>, Build>> {
+    let mut build_queue = HashMap::<&'a str, HashMap<Box<str>, Build>>::new();
+
+    let overrides = config.repo(&repo.name);
+
+    let series = config
+        .series
+        .keys()
+        .filter(|series| filters.series(series) && overrides.series(series));
 
-    for series in config.series.keys() {
+    for series in series {
         build_queue.insert(&series, HashMap::new());
     }
 
@@ -21,45 +50,107 @@ pub async fn build_queue<'a>(
         ref branches,
         ref name,
         ref directory,
+        ..
     } = repo;
 
-    // Concurrently generate git tar archives for each branch
-    let mut stream = branches
-        .iter()
-        .map(|branch| {
+    // Branches of the same commit share its archive, which is only generated once.
+    let mut commits = HashMap::<&str, Vec<&BranchTarget>>::new();
+    for branch in branches.iter() {
+        if filters.pocket(&branch.target.pocket) {
+            commits.entry(&branch.sha).or_default().push(&branch.target);
+        }
+    }
+
+    // Concurrently generate git tar archives for each commit
+    let mut stream = commits
+        .into_iter()
+        .map(|(sha, targets)| {
             async move {
-                info!("{} commit {}: building git tar", name, branch.sha);
+                info!("{} commit {}: building git tar", name, sha);
 
                 let archive_path = STRING_BUF.with(|buffer| {
                     let mut buffer = buffer.borrow_mut();
                     buffer.clear();
-                    buffer.push_str(&branch.sha);
+                    buffer.push_str(sha);
                     buffer.push_str(".tar");
                     git_dir.join(&*buffer)
                 });
 
-                let git_tar = GitTar::new(directory, &archive_path, &branch.sha)
-                    .await
-                    .unwrap();
+                let git_tar = GitTar::new(client, directory, &archive_path, sha).await;
 
-                (branch, git_tar)
+                (sha, targets, git_tar)
             }
         })
         .collect::<FuturesUnordered<_>>();
 
     // Collate the information as it is received from the stream.
-    while let Some((branch, git_tar)) = stream.next().await {
-        let (pocket, codename) = parse_branch(&branch.name);
+    while let Some((sha, targets, git_tar)) = stream.next().await {
+        let git_tar = git_tar.map_err(|why| {
+            let mut reason = String::new();
+            format_error(&why, |why| {
+                error!("{} commit {}: failed to build git tar: {}", name, sha, why);
+                reason = ["failed to build git tar: ", why.trim_end()].concat();
+            });
 
-        match codename {
-            Some(codename) => {
-                build_queue.entry(&codename).and_modify(|pockets| {
-                    pockets.insert(pocket, git_tar);
-                });
-            }
-            None => {
-                for pockets in build_queue.values_mut() {
-                    pockets.entry(&pocket).or_insert_with(|| git_tar.clone());
+            reason
+        });
+
+        for target in targets {
+            let BranchTarget {
+                pocket,
+                series: named,
+                release,
+            } = target;
+
+            // Branches without a series are only built for wildcard series.
+            let series = build_queue
+                .keys()
+                .cloned()
+                .filter(|series| {
+                    if named.is_empty() {
+                        config.series[*series].wildcard
+                    } else {
+                        named.iter().any(|named| &**named == *series)
+                    }
+                })
+                .collect::<Vec<&str>>();
+
+            let git_tar = match git_tar {
+                Ok(ref git_tar) => git_tar,
+                Err(ref reason) => {
+                    for series in series {
+                        let key = BuildKey {
+                            repo: name,
+                            commit: sha,
+                            series,
+                            pocket,
+                            arch: "source",
+                            stage: Stage::Source,
+                        };
+
+                        let result = state.finished(&key, Status::Failed, Some(&**reason), &[]);
+                        if let Err(why) = result {
+                            error!("{} commit {}: failed to record state: {}", name, sha, why);
+                        }
+                    }
+
+                    continue;
+                }
+            };
+
+            let build = Build {
+                git: git_tar.clone(),
+                release: *release,
+            };
+
+            for series in series {
+                let pockets = build_queue.get_mut(series).expect("series without pockets");
+                if named.is_empty() {
+                    pockets
+                        .entry(pocket.clone())
+                        .or_insert_with(|| build.clone());
+                } else {
+                    pockets.insert(pocket.clone(), build.clone());
                 }
             }
         }
@@ -68,8 +159,47 @@ pub async fn build_queue<'a>(
     build_queue
 }
 
-fn parse_branch(branch: &str) -> (&str, Option<&str>) {
-    let mut parts = branch.split('_');
-    let pocket = parts.next().expect("expected a branch pocket");
-    (pocket, parts.next())
+/// The pockets and series that a repository is built for, as the build queue assigns them,
+/// without archiving any of its commits.
+pub fn targets(
+    config: &Config,
+    filters: &Filters,
+    repo: &Repository,
+) -> HashSet<(Box<str>, Box<str>)> {
+    let overrides = config.repo(&repo.name);
+
+    let series = config
+        .series
+        .iter()
+        .filter(|(series, _)| filters.series(series) && overrides.series(series))
+        .collect::<Vec<_>>();
+
+    let mut targets = HashSet::new();
+
+    for branch in repo.branches.iter() {
+        let BranchTarget {
+            pocket,
+            series: named,
+            ..
+        } = &branch.target;
+
+        if !filters.pocket(pocket) {
+            continue;
+        }
+
+        for &(name, release) in &series {
+            // Branches without a series are only built for wildcard series.
+            let built = if named.is_empty() {
+                release.wildcard
+            } else {
+                named.contains(name)
+            };
+
+            if built {
+                targets.insert((pocket.clone(), name.clone()));
+            }
+        }
+    }
+
+    targets
 }
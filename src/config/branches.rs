@@ -0,0 +1,286 @@
+//! Rules which map the name of a branch to the pocket and series that it is built for.
+//!
+//! Rules are tried in order, and the first rule with a pattern that matches the branch
+//! decides what is built. Branches which match no rule are not built.
+
+use super::Error;
+use regex::Regex;
+
+/// A rule as it is written in a `[[branches]]` table of the config.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RawBranchRule {
+    /// Regular expression that must match the entire branch name.
+    #[serde(default)]
+    pub regex: Option<Box<str>>,
+
+    /// Glob that must match the entire branch name, where each `*` and `?` is a capture group.
+    #[serde(default)]
+    pub glob: Option<Box<str>>,
+
+    /// Pocket to build the branch for, which may refer to capture groups as `$1` or `${name}`.
+    #[serde(default)]
+    pub pocket: Option<Box<str>>,
+
+    /// Series to build the branch for, which are the wildcard series if empty.
+    #[serde(default)]
+    pub series: Vec<Box<str>>,
+
+    /// Matching branches are not built if this is false.
+    #[serde(default = "default_build")]
+    pub build: bool,
+}
+
+fn default_build() -> bool {
+    true
+}
+
+#[derive(Debug)]
+struct BranchRule {
+    pattern: Regex,
+    pocket: Box<str>,
+    series: Vec<Box<str>>,
+    build: bool,
+}
+
+/// Where a branch is to be built.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct BranchTarget {
+    pub pocket: Box<str>,
+    /// Series that the branch names, which are the wildcard series if empty.
+    pub series: Vec<Box<str>>,
+    /// Built with the version of its changelog, rather than a snapshot version.
+    pub release: bool,
+}
+
+#[derive(Debug)]
+pub struct BranchRules(Vec<BranchRule>);
+
+impl BranchRules {
+    /// Compiles the rules of the config, or the default rules if there are none.
+    pub fn new(raw: Vec<RawBranchRule>) -> Result<Self, Error> {
+        if raw.is_empty() {
+            return Ok(Self::default());
+        }
+
+        Self::compile(raw)
+    }
+
+    /// Compiles the rules of the config, which match nothing if there are none.
+    pub fn compile(raw: Vec<RawBranchRule>) -> Result<Self, Error> {
+        let mut rules = Vec::with_capacity(raw.len());
+
+        for rule in raw {
+            let pattern = match (rule.regex, rule.glob) {
+                (Some(regex), None) => ["^(?:", &regex, ")$"].concat(),
+                (None, Some(glob)) => glob_to_regex(&glob),
+                _ => return Err(Error::BranchPattern),
+            };
+
+            let pattern =
+                Regex::new(&pattern).map_err(|why| Error::BranchRegex(pattern.into(), why))?;
+
+            let pocket = match rule.pocket {
+                Some(pocket) => pocket,
+                None if !rule.build => Box::from(""),
+                None => return Err(Error::BranchPocket(pattern.as_str().into())),
+            };
+
+            rules.push(BranchRule {
+                pattern,
+                pocket,
+                series: rule.series,
+                build: rule.build,
+            });
+        }
+
+        Ok(BranchRules(rules))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Finds where a branch is to be built, or `None` if it is not to be built.
+    pub fn map(&self, branch: &str) -> Option<BranchTarget> {
+        let (rule, captures) = self
+            .0
+            .iter()
+            .filter_map(|rule| rule.pattern.captures(branch).map(|caps| (rule, caps)))
+            .next()?;
+
+        if !rule.build {
+            return None;
+        }
+
+        let expand = |template: &str| {
+            let mut expanded = String::new();
+            captures.expand(template, &mut expanded);
+            expanded
+        };
+
+        let pocket = expand(&rule.pocket);
+        if pocket.is_empty() {
+            return None;
+        }
+
+        let series = rule
+            .series
+            .iter()
+            .map(|series| Box::from(expand(series)))
+            .collect();
+
+        Some(BranchTarget {
+            pocket: pocket.into(),
+            series,
+            release: false,
+        })
+    }
+}
+
+impl Default for BranchRules {
+    /// Builds `pocket` branches for the wildcard series, and `pocket_codename` branches for
+    /// the named series. Branches containing a `/` are not built.
+    fn default() -> Self {
+        let rule = |pattern: &str, series: &[&str]| BranchRule {
+            pattern: Regex::new(pattern).expect("invalid default branch rule"),
+            pocket: "$1".into(),
+            series: series.iter().map(|&series| Box::from(series)).collect(),
+            build: true,
+        };
+
+        BranchRules(vec![
+            rule("^([^_/]+)_([^_/]+)(?:_[^/]*)?$", &["$2"]),
+            rule("^([^_/]+)$", &[]),
+        ])
+    }
+}
+
+/// Converts a glob into an anchored regular expression, capturing each wildcard.
+fn glob_to_regex(glob: &str) -> String {
+    let mut pattern = String::from("^");
+    let mut literal = [0; 4];
+
+    for character in glob.chars() {
+        match character {
+            '*' => pattern.push_str("(.*)"),
+            '?' => pattern.push_str("(.)"),
+            _ => pattern.push_str(&regex::escape(character.encode_utf8(&mut literal))),
+        }
+    }
+
+    pattern.push('$');
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(pocket: &str, series: &[&str]) -> Option<BranchTarget> {
+        Some(BranchTarget {
+            pocket: pocket.into(),
+            series: series.iter().map(|&series| Box::from(series)).collect(),
+            release: false,
+        })
+    }
+
+    fn raw(glob: &str, pocket: Option<&str>, series: &[&str], build: bool) -> RawBranchRule {
+        RawBranchRule {
+            regex: None,
+            glob: Some(glob.into()),
+            pocket: pocket.map(Box::from),
+            series: series.iter().map(|&series| Box::from(series)).collect(),
+            build,
+        }
+    }
+
+    #[test]
+    fn default_rules_split_on_underscores() {
+        let rules = BranchRules::default();
+
+        assert_eq!(rules.map("master"), target("master", &[]));
+        assert_eq!(rules.map("master_eoan"), target("master", &["eoan"]));
+        assert_eq!(
+            rules.map("proposed_bionic"),
+            target("proposed", &["bionic"])
+        );
+
+        // Parts after the codename are ignored, as they were by `collate::parse_branch`.
+        assert_eq!(rules.map("master_eoan_hotfix"), target("master", &["eoan"]));
+        assert_eq!(rules.map("master_eoan_a_b"), target("master", &["eoan"]));
+    }
+
+    #[test]
+    fn default_rules_skip_slashes() {
+        let rules = BranchRules::default();
+
+        assert_eq!(rules.map("feature/x"), None);
+        assert_eq!(rules.map("feature/x_eoan"), None);
+        assert_eq!(rules.map("master_eoan/x"), None);
+    }
+
+    #[test]
+    fn empty_config_uses_default_rules() {
+        let rules = BranchRules::new(Vec::new()).unwrap();
+        assert_eq!(rules.map("master_eoan"), target("master", &["eoan"]));
+
+        let rules = BranchRules::compile(Vec::new()).unwrap();
+        assert!(rules.is_empty());
+        assert_eq!(rules.map("master"), None);
+    }
+
+    #[test]
+    fn globs_capture_wildcards() {
+        assert_eq!(glob_to_regex("feature/*"), "^feature/(.*)$");
+        assert_eq!(glob_to_regex("v?.*"), "^v(.)\\.(.*)$");
+
+        let rules = BranchRules::compile(vec![
+            raw("feature/*_*", Some("feature-$1"), &["$2"], true),
+            raw("feature/*", Some("feature-$1"), &[], true),
+        ])
+        .unwrap();
+
+        assert_eq!(rules.map("feature/x"), target("feature-x", &[]));
+        assert_eq!(rules.map("feature/x_eoan"), target("feature-x", &["eoan"]));
+        assert_eq!(rules.map("featurex"), None);
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = BranchRules::compile(vec![
+            raw("wip/*", None, &[], false),
+            raw("*", Some("$1"), &[], true),
+        ])
+        .unwrap();
+
+        assert_eq!(rules.map("wip/x"), None);
+        assert_eq!(rules.map("master"), target("master", &[]));
+    }
+
+    #[test]
+    fn regex_rules_are_anchored() {
+        let rules = BranchRules::compile(vec![RawBranchRule {
+            regex: Some("release-(?P<series>[a-z]+)".into()),
+            glob: None,
+            pocket: Some("release".into()),
+            series: vec!["${series}".into()],
+            build: true,
+        }])
+        .unwrap();
+
+        assert_eq!(rules.map("release-eoan"), target("release", &["eoan"]));
+        assert_eq!(rules.map("old-release-eoan"), None);
+        assert_eq!(rules.map("release-eoan-1"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let both = RawBranchRule {
+            regex: Some("x".into()),
+            ..raw("x", Some("x"), &[], true)
+        };
+
+        assert!(BranchRules::compile(vec![both]).is_err());
+        assert!(BranchRules::compile(vec![raw("x", None, &[], true)]).is_err());
+    }
+}
@@ -10,6 +10,10 @@ pub struct ConfigDirs {
     pub binary: PathBuf,
     pub build: PathBuf,
     pub git: PathBuf,
+    /// Responses of the GitHub API, cached for conditional requests.
+    pub github: PathBuf,
+    /// Bare mirrors of every repository, which commits are archived from.
+    pub mirror: PathBuf,
     pub repo: PathBuf,
     pub source: PathBuf,
 }
@@ -22,6 +26,18 @@ impl ConfigDirs {
             source,
         })?;
 
+        dir = &self.github;
+        fs::create_dir_all(dir).map_err(|source| DirError::Create {
+            dir: dir.into(),
+            source,
+        })?;
+
+        dir = &self.mirror;
+        fs::create_dir_all(dir).map_err(|source| DirError::Create {
+            dir: dir.into(),
+            source,
+        })?;
+
         dir = &self.source;
         fs::create_dir_all(dir).map_err(|source| DirError::Create {
             dir: dir.into(),
@@ -35,13 +51,6 @@ impl ConfigDirs {
         })?;
 
         dir = &self.repo;
-        if dir.exists() {
-            fs::remove_dir_all(dir).map_err(|source| DirError::Remove {
-                dir: dir.into(),
-                source,
-            })?;
-        }
-
         fs::create_dir_all(dir).map_err(|source| DirError::Create {
             dir: dir.into(),
             source,
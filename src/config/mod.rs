@@ -1,12 +1,24 @@
+mod branches;
 mod dirs;
 
-pub use self::dirs::ConfigDirs;
+pub use self::{
+    branches::{BranchRules, BranchTarget, RawBranchRule},
+    dirs::ConfigDirs,
+};
 
 use crate::errors::DirError;
-use std::{collections::HashMap, env, fs, io, path::Path};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env, fs, io, path::Path, time::Duration};
 
 #[derive(Debug, Error)]
 pub enum Error {
+    #[error("branch rules must have either a regex or a glob")]
+    BranchPattern,
+    #[error("branch rule {} must have a pocket", _0)]
+    BranchPocket(Box<str>),
+    #[error("invalid branch rule pattern: {}", _0)]
+    BranchRegex(Box<str>, #[source] regex::Error),
     #[error("failed to create initial directories")]
     Directory(#[from] DirError),
     #[error("config.toml was not found in the current working directory")]
@@ -21,12 +33,24 @@ pub enum Error {
 pub struct Config {
     pub archs: HashMap<Box<str>, bool>,
     pub series: HashMap<Box<str>, ConfigSeries>,
+    pub branches: BranchRules,
     pub github: ConfigGitHub,
+    pub remotes: Vec<ConfigRemote>,
+    pub fixtures: Option<Box<str>>,
+    pub webhook: ConfigWebhook,
+    pub pull_requests: ConfigPullRequests,
+    pub releases: ConfigReleases,
+    /// Identifies the branch, release, and pull request rules, which decide what is built.
+    pub rules: Box<str>,
     pub email: Box<str>,
     pub fullname: Box<str>,
     pub context: Box<str>,
     pub description: Box<str>,
     pub build_url: Option<Box<str>>,
+    pub checks: bool,
+    pub blacklist_ttl_hours: Option<u64>,
+    pub repos: HashMap<Box<str>, ConfigRepo>,
+    pub extra_repositories: Vec<ConfigExtraRepository>,
     pub dirs: ConfigDirs,
     pub concurrent_builds: usize,
     pub dev: bool,
@@ -42,14 +66,29 @@ impl Config {
 
         let raw = fs::read_to_string(config_path).map_err(Error::Read)?;
         let raw_config = toml::from_str::<RawConfig>(&raw)?;
+        let rules = rules_id(&raw_config);
 
         Ok(Self {
             archs: raw_config.archs,
             build_url: raw_config.build_url,
+            checks: raw_config.checks,
+            blacklist_ttl_hours: raw_config.blacklist_ttl_hours,
+            repos: raw_config.repos,
+            extra_repositories: raw_config.extra_repositories,
             context: raw_config.context,
             description: raw_config.description,
             series: raw_config.series,
+            branches: BranchRules::new(raw_config.branches)?,
             github: raw_config.github,
+            remotes: raw_config.remotes,
+            fixtures: raw_config.fixtures,
+            webhook: raw_config.webhook,
+            pull_requests: raw_config.pull_requests,
+            releases: ConfigReleases {
+                tags: BranchRules::compile(raw_config.releases.tags)?,
+                series_suffix: raw_config.releases.series_suffix,
+            },
+            rules,
             email: raw_config.email,
             fullname: raw_config.fullname,
             concurrent_builds: raw_config.concurrent_builds,
@@ -63,6 +102,8 @@ impl Config {
                     base,
                     binary: build.join("binary"),
                     git: build.join("git"),
+                    github: build.join("github"),
+                    mirror: build.join("mirrors"),
                     repo: build.join("repos"),
                     source: build.join("source"),
                     build,
@@ -72,6 +113,34 @@ impl Config {
             },
         })
     }
+
+    /// The overrides of a repository, which are the defaults if it has none.
+    pub fn repo(&self, name: &str) -> &ConfigRepo {
+        static DEFAULT: ConfigRepo = ConfigRepo {
+            changelog: None,
+            pre_source: Vec::new(),
+            exclude_binaries: Vec::new(),
+            series: Vec::new(),
+            archs: Vec::new(),
+            sbuild_args: Vec::new(),
+            source_timeout_minutes: None,
+            binary_timeout_minutes: None,
+        };
+
+        self.repos.get(name).unwrap_or(&DEFAULT)
+    }
+}
+
+/// Hashes the rules which decide what is built, as they were written in the config.
+fn rules_id(raw_config: &RawConfig) -> Box<str> {
+    let rules = (
+        &raw_config.branches,
+        &raw_config.releases,
+        &raw_config.pull_requests,
+    );
+
+    let json = serde_json::to_vec(&rules).expect("failed to serialize rules");
+    format!("{:x}", Sha256::digest(&json)).into()
 }
 
 fn check_env(key: &str) -> bool {
@@ -82,13 +151,56 @@ fn check_env(key: &str) -> bool {
 struct RawConfig {
     pub archs: HashMap<Box<str>, bool>,
     pub series: HashMap<Box<str>, ConfigSeries>,
+
+    /// Rules which map branches to pockets and series, replacing the default rules.
+    #[serde(default)]
+    pub branches: Vec<RawBranchRule>,
+
     pub github: ConfigGitHub,
+
+    /// Repositories outside of GitHub, which are cloned from their URL.
+    #[serde(default)]
+    pub remotes: Vec<ConfigRemote>,
+
+    /// Directory of fixtures to fetch repositories from instead of GitHub, for offline tests.
+    #[serde(default)]
+    pub fixtures: Option<Box<str>>,
+
+    /// Settings of the webhook receiver of the `serve` mode.
+    #[serde(default)]
+    pub webhook: ConfigWebhook,
+
+    /// Builds of the open pull requests of GitHub repositories.
+    #[serde(default)]
+    pub pull_requests: ConfigPullRequests,
+
+    /// Release builds of tags, which are versioned by their changelog.
+    #[serde(default)]
+    pub releases: RawConfigReleases,
+
     pub email: Box<str>,
     pub fullname: Box<str>,
     pub context: Box<str>,
     pub description: Box<str>,
     pub build_url: Option<Box<str>>,
 
+    /// Creates GitHub check runs with build logs, which requires a GitHub App token.
+    #[serde(default)]
+    pub checks: bool,
+
+    /// Hours until commits which failed to build are automatically retried.
+    #[serde(default)]
+    pub blacklist_ttl_hours: Option<u64>,
+
+    /// Overrides of the build behavior of individual repositories.
+    #[serde(default)]
+    pub repos: HashMap<Box<str>, ConfigRepo>,
+
+    /// Apt repositories that sbuild installs build dependencies from, which are the Ubuntu
+    /// updates and security pockets and the Pop PPAs if none are configured.
+    #[serde(default = "default_extra_repositories")]
+    pub extra_repositories: Vec<ConfigExtraRepository>,
+
     #[default = 1]
     pub concurrent_builds: usize,
 }
@@ -97,21 +209,411 @@ struct RawConfig {
 pub struct ConfigGitHub {
     #[serde(default)]
     pub organizations: Vec<ConfigOrganization>,
+
+    /// Individual repositories to build, as `owner/name`.
     #[serde(default)]
     pub repos: Vec<Box<str>>,
+
+    /// Reuses the branches of repositories which were not pushed to since they were fetched,
+    /// unless pull requests are built.
+    #[serde(default = "default_true")]
+    pub skip_unchanged: bool,
+
+    #[serde(default)]
+    pub auth: ConfigGitHubAuth,
+}
+
+/// Credentials for the GitHub API, which are read from `.github_token` if none are given.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ConfigGitHubAuth {
+    /// Path of a file containing a token.
+    pub token_file: Option<Box<str>>,
+
+    /// Name of an environment variable containing a token.
+    pub token_env: Option<Box<str>>,
+
+    /// GitHub App to authenticate as an installation of.
+    pub app: Option<ConfigGitHubApp>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfigGitHubApp {
+    pub id: u64,
+    pub installation_id: u64,
+
+    /// Path of the PEM-encoded private key of the app.
+    pub private_key: Box<str>,
+}
+
+#[derive(Debug, Deserialize, SmartDefault)]
+#[serde(default)]
+pub struct ConfigWebhook {
+    /// Address and port to listen for deliveries on.
+    #[default = "127.0.0.1:8088"]
+    pub listen: Box<str>,
+
+    /// Path of a file containing the secret that deliveries are signed with.
+    #[default = ".webhook_secret"]
+    pub secret_file: Box<str>,
+}
+
+#[derive(Debug, Deserialize, Serialize, SmartDefault)]
+#[serde(default)]
+pub struct ConfigPullRequests {
+    /// Open pull requests are only built if this is true.
+    pub build: bool,
+
+    /// Pocket that a pull request is built for, where `{number}` is replaced by its number.
+    #[default = "pr-{number}"]
+    pub pocket: Box<str>,
+}
+
+impl ConfigPullRequests {
+    /// The pocket of a pull request.
+    pub fn pocket(&self, number: u64) -> Box<str> {
+        self.pocket.replace("{number}", &number.to_string()).into()
+    }
+}
+
+/// Release builds of tags, which use the version of their changelog instead of a snapshot
+/// version.
+#[derive(Debug)]
+pub struct ConfigReleases {
+    /// Rules which map tags to pockets and series. Tags are not built if there are none.
+    pub tags: BranchRules,
+
+    /// Appends `~<release>` to the version, so that every series has a distinct version.
+    /// Without it, releases are built in a directory for each series.
+    pub series_suffix: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, SmartDefault)]
+#[serde(default)]
+struct RawConfigReleases {
+    tags: Vec<RawBranchRule>,
+
+    #[default = true]
+    series_suffix: bool,
+}
+
+/// A repository which is cloned from any git URL, with branches discovered by `git ls-remote`.
+#[derive(Debug, Deserialize)]
+pub struct ConfigRemote {
+    /// Name of the repository, which is also the name of its clone.
+    pub name: Box<str>,
+    pub url: Box<str>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
 pub struct ConfigOrganization {
     pub name: Box<str>,
 
-    /// Filter repositories with names that start with
+    /// Only selects repositories with names that start with this prefix.
     #[serde(default)]
     pub starts_filter: Option<Box<str>>,
+
+    /// Only selects repositories with names that match one of these regexes.
+    #[serde(default, deserialize_with = "deserialize_regexes")]
+    pub include: Vec<Regex>,
+
+    /// Never selects repositories with names that match one of these regexes.
+    #[serde(default, deserialize_with = "deserialize_regexes")]
+    pub exclude: Vec<Regex>,
+
+    /// Only selects repositories which have one of these GitHub topics.
+    #[serde(default)]
+    pub topics: Vec<Box<str>>,
+
+    /// Never selects repositories which have one of these GitHub topics.
+    #[serde(default)]
+    pub exclude_topics: Vec<Box<str>>,
+
+    /// Only selects archived repositories if true, or unarchived repositories if false.
+    #[serde(default)]
+    pub archived: Option<bool>,
+
+    /// Only selects forks if true, or repositories which are not forks if false.
+    #[serde(default)]
+    pub fork: Option<bool>,
+
+    /// Only selects repositories with this visibility.
+    #[serde(default)]
+    pub visibility: Option<Visibility>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    Public,
+    Private,
+}
+
+fn deserialize_regexes<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Regex>, D::Error> {
+    <Vec<Box<str>> as serde::Deserialize>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| Regex::new(pattern).map_err(serde::de::Error::custom))
+        .collect()
 }
 
 #[derive(Debug, Default, Deserialize)]
 pub struct ConfigSeries {
     pub release: Box<str>,
+
+    /// Builds branches which do not name a series, rather than only `pocket_codename` branches.
     pub wildcard: bool,
 }
+
+/// An apt repository which is added to sbuild chroots, configured by `[[extra_repositories]]`.
+///
+/// Occurrences of `{series}` in the suite are replaced by the codename of the series being built.
+#[derive(Debug, Deserialize)]
+pub struct ConfigExtraRepository {
+    pub uri: Box<str>,
+
+    #[serde(default = "default_suite")]
+    pub suite: Box<str>,
+
+    #[serde(default = "default_components")]
+    pub components: Box<str>,
+
+    /// Also adds the `deb-src` line of the repository.
+    #[serde(default = "default_true")]
+    pub source: bool,
+
+    /// Path of the key that the repository is signed with, relative to `dirs.base`.
+    #[serde(default)]
+    pub key: Option<Box<str>>,
+
+    /// Series to add the repository to, which are all series if empty.
+    #[serde(default)]
+    pub series: Vec<Box<str>>,
+
+    /// Only adds the repository for dev builds if true, or for non-dev builds if false.
+    #[serde(default)]
+    pub dev: Option<bool>,
+}
+
+impl ConfigExtraRepository {
+    /// Whether the repository is added to builds of the series.
+    pub fn applies(&self, series: &str, dev: bool) -> bool {
+        self.dev.map_or(true, |only_dev| only_dev == dev)
+            && (self.series.is_empty() || self.series.iter().any(|value| &**value == series))
+    }
+
+    /// The sources.list lines of the repository for a series.
+    pub fn lines(&self, series: &str) -> Vec<String> {
+        let suite = self.suite.replace("{series}", series);
+        let kinds: &[&str] = if self.source {
+            &["deb", "deb-src"]
+        } else {
+            &["deb"]
+        };
+
+        kinds
+            .iter()
+            .map(|&kind| [kind, " ", &self.uri, " ", &suite, " ", &self.components].concat())
+            .collect()
+    }
+}
+
+fn default_extra_repositories() -> Vec<ConfigExtraRepository> {
+    let ubuntu = |suite: &str| ConfigExtraRepository {
+        uri: "http://us.archive.ubuntu.com/ubuntu/".into(),
+        suite: suite.into(),
+        components: "main restricted universe multiverse".into(),
+        source: true,
+        key: None,
+        series: Vec::new(),
+        dev: None,
+    };
+
+    let ppa = |name: &str, key: &str, dev: bool| ConfigExtraRepository {
+        uri: ["http://ppa.launchpad.net/", name, "/ubuntu"]
+            .concat()
+            .into(),
+        suite: default_suite(),
+        components: default_components(),
+        source: true,
+        key: Some(key.into()),
+        series: Vec::new(),
+        dev: Some(dev),
+    };
+
+    vec![
+        ubuntu("{series}-updates"),
+        ubuntu("{series}-security"),
+        ppa("system76/pop", ".ppa.asc", false),
+        ppa("system76/proposed", ".ppa.asc", false),
+        ppa("system76-dev/stable", ".ppa-dev.asc", true),
+        ppa("system76-dev/pre-stable", ".ppa-dev.asc", true),
+    ]
+}
+
+fn default_suite() -> Box<str> {
+    "{series}".into()
+}
+
+fn default_components() -> Box<str> {
+    "main".into()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Build behavior of a repository, which is configured by a `[repos.<name>]` table.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ConfigRepo {
+    /// Path of the changelog to append the build entry to, relative to the source root.
+    pub changelog: Option<Box<str>>,
+
+    /// Commands to execute in the source root after patches are applied.
+    pub pre_source: Vec<Vec<Box<str>>>,
+
+    /// Binary packages with names matching these patterns are not built.
+    ///
+    /// A `*` in a pattern matches any sequence of characters.
+    pub exclude_binaries: Vec<Box<str>>,
+
+    /// Series to build for, which are all configured series if empty.
+    pub series: Vec<Box<str>>,
+
+    /// Archs to build binaries for, which are all configured archs if empty.
+    pub archs: Vec<Box<str>>,
+
+    /// Extra arguments to pass to sbuild.
+    pub sbuild_args: Vec<Box<str>>,
+
+    /// Minutes until a source build is aborted.
+    pub source_timeout_minutes: Option<u64>,
+
+    /// Minutes until a binary build is aborted.
+    pub binary_timeout_minutes: Option<u64>,
+}
+
+impl ConfigRepo {
+    pub fn changelog(&self) -> &str {
+        self.changelog
+            .as_ref()
+            .map_or("debian/changelog", |path| path.as_ref())
+    }
+
+    pub fn excludes_binary(&self, binary: &str) -> bool {
+        self.exclude_binaries
+            .iter()
+            .any(|pattern| wildcard_match(pattern, binary))
+    }
+
+    pub fn series(&self, series: &str) -> bool {
+        self.series.is_empty() || self.series.iter().any(|value| &**value == series)
+    }
+
+    pub fn arch(&self, arch: &str) -> bool {
+        self.archs.is_empty() || self.archs.iter().any(|value| &**value == arch)
+    }
+
+    pub fn source_timeout(&self) -> Option<Duration> {
+        self.source_timeout_minutes
+            .map(|minutes| Duration::from_secs(minutes * 60))
+    }
+
+    pub fn binary_timeout(&self) -> Option<Duration> {
+        self.binary_timeout_minutes
+            .map(|minutes| Duration::from_secs(minutes * 60))
+    }
+}
+
+/// Matches a name against a pattern, where `*` matches any sequence of characters.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+
+    if !name.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &name[first.len()..];
+    let mut parts = parts.peekable();
+
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.len() >= part.len() && rest.ends_with(part);
+        }
+
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_matches() {
+        let matches = [
+            ("systemd", "systemd"),
+            ("*", ""),
+            ("*", "anything"),
+            ("*-dbgsym", "linux-image-dbgsym"),
+            ("linux-udebs-*", "linux-udebs-generic"),
+            ("linux-*-generic", "linux-image-generic"),
+            ("*-udeb*", "systemd-udeb"),
+            ("a*b*c", "abc"),
+            ("a*b*c", "axxbyyc"),
+            ("a*a", "aa"),
+        ];
+
+        for &(pattern, name) in &matches {
+            assert!(
+                wildcard_match(pattern, name),
+                "{} should match {}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn wildcard_mismatches() {
+        let mismatches = [
+            ("systemd", "systemd-udeb"),
+            ("systemd", "libsystemd"),
+            ("*-dbgsym", "linux-image"),
+            ("linux-udebs-*", "linux-image"),
+            ("linux-*-generic", "linux-image-lowlatency"),
+            ("a*b*c", "acb"),
+            ("a*a", "a"),
+            ("", "a"),
+        ];
+
+        for &(pattern, name) in &mismatches {
+            assert!(
+                !wildcard_match(pattern, name),
+                "{} should not match {}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn excludes_binaries() {
+        let repo = ConfigRepo {
+            exclude_binaries: vec!["*-dbgsym".into(), "linux-udebs-*".into()],
+            ..ConfigRepo::default()
+        };
+
+        assert!(repo.excludes_binary("linux-image-dbgsym"));
+        assert!(repo.excludes_binary("linux-udebs-generic"));
+        assert!(!repo.excludes_binary("linux-image"));
+    }
+}
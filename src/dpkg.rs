@@ -1,13 +1,16 @@
 use crate::{
+    checks,
     config::{Config, ConfigSeries},
     fetcher::Repository,
+    forge::Forge,
     git::GitTar,
-    github::{self, StatusContext},
-    misc::{check_call, check_output},
+    github::StatusContext,
+    misc::{check_call, check_call_captured, check_call_timeout, check_output},
+    state::{self, BuildKey, State, Status},
 };
 
 use anyhow::Context;
-use chrono::DateTime;
+use chrono::{DateTime, Duration, Utc};
 use debian_changelog::{r#async::append as changelog_append, Entry as ChangelogEntry};
 use reqwest::Client;
 
@@ -16,6 +19,7 @@ use std::{
     env, io,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration as StdDuration,
 };
 
 use tokio::{
@@ -23,16 +27,23 @@ use tokio::{
     io::{AsyncRead, AsyncReadExt},
 };
 
-pub struct Dpkg<'a> {
+pub struct Dpkg<'a, F: Forge> {
     pub config: &'a Config,
     pub client: &'a Arc<Client>,
+    pub forge: &'a F,
+    pub state: &'a State,
+    /// The GitHub owner of the repository, which has no statuses to report if `None`.
+    pub owner: Option<&'a str>,
     pub repo: &'a Repository,
     pub codename: &'a str,
+    pub pocket: &'a str,
     pub release: &'a ConfigSeries,
     pub git: &'a GitTar,
+    /// A release build of a tag, which is versioned by its changelog.
+    pub tagged: bool,
 }
 
-impl<'a> Dpkg<'a> {
+impl<'a, F: Forge> Dpkg<'a, F> {
     pub async fn binary(
         &self,
         path_version: &str,
@@ -47,8 +58,11 @@ impl<'a> Dpkg<'a> {
             codename,
             release,
             git,
+            ..
         } = self;
 
+        let overrides = config.repo(&repo.name);
+
         let dsc = read_to_string(dsc_path)
             .await
             .context("failed to read dsc file")?;
@@ -56,6 +70,7 @@ impl<'a> Dpkg<'a> {
         let (source_name, version, package_list) =
             parse_dsc(&dsc).context("failed to parse dsc file")?;
 
+        let binary_dir = &self.binary_dir();
         let mut debs: Vec<Box<Path>> = Vec::new();
         let mut found_binaries = true;
 
@@ -69,17 +84,7 @@ impl<'a> Dpkg<'a> {
                 .next()
                 .context("failed to find kind field in package list")?;
 
-            // Filter packages which are not required for Linux.
-            let linux_non_requirement = || {
-                &*self.repo.name == "linux"
-                    && (binary.ends_with("-dbgsym") || binary.starts_with("linux-udebs-"))
-            };
-
-            // Filter packages which are not required for systemd.
-            let systemd_non_requirement =
-                || &*self.repo.name == "systemd" && binary.ends_with("-udeb");
-
-            if kind == "udeb" || linux_non_requirement() || systemd_non_requirement() {
+            if kind == "udeb" || overrides.excludes_binary(binary) {
                 continue;
             }
 
@@ -99,7 +104,7 @@ impl<'a> Dpkg<'a> {
                 };
 
                 let filename = [binary, "_", path_version, "_", deb_arch, ".deb"].concat();
-                let deb_path = self.config.dirs.binary.join(&filename);
+                let deb_path = binary_dir.join(&filename);
 
                 if !deb_path.exists() {
                     found_binaries = false;
@@ -115,16 +120,21 @@ impl<'a> Dpkg<'a> {
         }
 
         let logname = [source_name, "_", path_version, "_", build_arch, ".build"].concat();
-        let build_log = self.config.dirs.binary.join(&logname);
+        let build_log = binary_dir.join(&logname);
+
+        let key = self.key(build_arch);
+        let stage = ["binary-", build_arch].concat();
 
         if found_binaries {
             info!(
                 "{} commit {} on {}: binaries for {} already built",
                 source_name, git.id, codename, build_arch
             );
-        } else if build_log.exists() {
+
+            self.existing(&key, &debs);
+        } else if self.blacklisted(&key) {
             info!(
-                "{} commit {} on {}: binaries for {} already failed to build",
+                "{} commit {} on {}: binaries for {} are blacklisted",
                 source_name, git.id, codename, build_arch
             );
         } else {
@@ -133,108 +143,74 @@ impl<'a> Dpkg<'a> {
                 source_name, git.id, codename, build_arch
             );
 
-            // github_status(name, git.id, series.codename + "/binary-" + build_arch, "pending")
-
-            let (ppa_key, ppa_release, ppa_proposed) = if config.dev {
-                (
-                    ".ppa-dev.asc",
-                    "system76-dev/stable",
-                    "system76-dev/pre-stable",
-                )
-            } else {
-                (".ppa.asc", "system76/pop", "system76/proposed")
-            };
-
-            let key_path = config.dirs.base.join(ppa_key);
+            self.started(&stage, &key).await;
 
             let mut sbuild_args: Vec<String> = vec![
                 ["--arch=", build_arch].concat(),
                 ["--dist=", codename].concat(),
-                [
-                    "--extra-repository=deb http://us.archive.ubuntu.com/ubuntu/ ",
-                    codename,
-                    "-updates main restricted universe multiverse",
-                ]
-                .concat(),
-                [
-                    "--extra-repository=deb-src http://us.archive.ubuntu.com/ubuntu/ ",
-                    codename,
-                    "-updates main restricted universe multiverse",
-                ]
-                .concat(),
-                [
-                    "--extra-repository=deb http://us.archive.ubuntu.com/ubuntu/ ",
-                    codename,
-                    "-security main restricted universe multiverse",
-                ]
-                .concat(),
-                [
-                    "--extra-repository=deb-src http://us.archive.ubuntu.com/ubuntu/ ",
-                    codename,
-                    "-security main restricted universe multiverse",
-                ]
-                .concat(),
-                [
-                    "--extra-repository=deb http://ppa.launchpad.net/",
-                    ppa_release,
-                    "/ubuntu ",
-                    codename,
-                    " main",
-                ]
-                .concat(),
-                [
-                    "--extra-repository=deb-src http://ppa.launchpad.net/",
-                    ppa_release,
-                    "/ubuntu ",
-                    codename,
-                    " main",
-                ]
-                .concat(),
-                [
-                    "--extra-repository=deb http://ppa.launchpad.net/",
-                    ppa_proposed,
-                    "/ubuntu ",
-                    codename,
-                    " main",
-                ]
-                .concat(),
-                [
-                    "--extra-repository=deb-src http://ppa.launchpad.net/",
-                    ppa_proposed,
-                    "/ubuntu ",
-                    codename,
-                    " main",
-                ]
-                .concat(),
-                ["--extra-repository-key=", key_path.to_str().unwrap()].concat(),
             ];
 
+            let mut keys = Vec::new();
+            for extra in &config.extra_repositories {
+                if !extra.applies(codename, config.dev) {
+                    continue;
+                }
+
+                for line in extra.lines(codename) {
+                    sbuild_args.push(["--extra-repository=", &line].concat());
+                }
+
+                if let Some(ref key) = extra.key {
+                    if !keys.contains(key) {
+                        keys.push(key.clone());
+                    }
+                }
+            }
+
+            for key in keys {
+                let key_path = config.dirs.base.join(&*key);
+                sbuild_args.push(["--extra-repository-key=", key_path.to_str().unwrap()].concat());
+            }
+
             if build_all {
                 sbuild_args.push("--arch-all".into());
             }
 
+            sbuild_args.extend(overrides.sbuild_args.iter().map(|arg| arg.to_string()));
+
             sbuild_args.push(dsc_path.to_str().expect("dsc path is not UTF-8").into());
 
+            fs::create_dir_all(binary_dir)
+                .await
+                .context("failed to create binary directory")?;
+
             info!("building {} with sbuild", repo.name);
-            match check_call("sbuild", &sbuild_args, Some(&config.dirs.binary)).await {
+            let result = check_call_timeout(
+                "sbuild",
+                &sbuild_args,
+                Some(binary_dir),
+                overrides.binary_timeout(),
+            )
+            .await;
+
+            match result {
                 Ok(()) => {
                     info!(
                         "{} commit {} on {}: finished building binaries for {}",
                         source_name, git.id, codename, build_arch
                     );
 
-                    // github_status(name, git.id, series.codename + "/binary-" + build_arch, "success")
+                    self.completed(&stage, &key, None, &debs).await;
                 }
                 Err(why) => {
-                    //     github_status(name, git.id, series.codename + "/binary-" + build_arch, "failure")
-                    // except Exception as ex_s:
-                    //     print("\x1B[1m{} commit {} on {}: failed to report build failure: {!r}\x1B[0m\n".format(source_name, git.id, series.codename, ex_s))
-
-                    let context = read_to_string(dbg!(&build_log))
+                    let context = read_to_string(&build_log)
                         .await
                         .unwrap_or_else(|why| format!("failed to read build log: {}", why));
 
-                    return Err(anyhow!("{}: {}", why, context));
+                    let error = anyhow!("{}: {}", why, context);
+                    self.completed(&stage, &key, Some(&error), &[build_log.into()])
+                        .await;
+                    return Err(error);
                 }
             }
         }
@@ -264,12 +240,9 @@ impl<'a> Dpkg<'a> {
             ..
         } = self;
 
-        let source_dir = &self.config.dirs.source;
+        let source_dir = &self.source_dir();
         let extract_dir: &Path = &source_dir.join(&[&git.id, "_", codename].concat());
         let debian_path = extract_dir.join("debian");
-        let patches_dir = debian_path.join("patches");
-
-        let is_linux = &*self.repo.name == "linux";
 
         if extract_dir.is_dir() {
             fs::remove_dir_all(extract_dir)
@@ -286,7 +259,18 @@ impl<'a> Dpkg<'a> {
             .await
             .context("failed to extract git tar")?;
 
-        ensure!(debian_path.is_dir(), "no debian dir");
+        let key = self.key("source");
+
+        if !debian_path.is_dir() {
+            if let Err(why) = self.state.finished(&key, Status::NoDebian, None, &[]) {
+                error!(
+                    "{} commit {}: failed to record state: {}",
+                    self.repo.name, git.id, why
+                );
+            }
+
+            bail!("no debian dir");
+        }
 
         let control = read_to_string(&debian_path.join("control"))
             .await
@@ -305,13 +289,19 @@ impl<'a> Dpkg<'a> {
 
         changelog_version.pop();
 
-        let version = [
-            &*changelog_version,
-            &*git.timestamp,
-            &*release.release,
-            &git.id[..7],
-        ]
-        .join("~");
+        let version = if !self.tagged {
+            [
+                &*changelog_version,
+                &*git.timestamp,
+                &*release.release,
+                &git.id[..7],
+            ]
+            .join("~")
+        } else if config.releases.series_suffix {
+            [&*changelog_version, "~", &*release.release].concat()
+        } else {
+            changelog_version.clone()
+        };
 
         // if dev {
         //     version.push_str("dev");
@@ -326,32 +316,102 @@ impl<'a> Dpkg<'a> {
                 "{} commit {} on {}: source already built",
                 source_name, git.id, codename
             );
+
+            self.existing(
+                &key,
+                &[dsc_path.as_path().into(), tar_path.as_path().into()],
+            );
         } else {
             info!(
                 "{} commit {} on {}: building source",
                 source_name, git.id, codename
             );
 
-            if let Some(target_url) = config.build_url.as_ref() {
-                let context_ctx = [codename, "/source"].concat();
-                let context = [&config.context, "/", &context_ctx].concat();
-                let description = [&config.description, " ", &context_ctx].concat();
-                let state = "pending";
+            self.started("source", &key).await;
 
-                // let ctx = StatusContext {
-                //     context: &context,
-                //     description: &description,
-                //     state: &state,
-                //     target_url: &target_url,
-                // };
+            if let Err(why) = self
+                .prepare_source(extract_dir, source_name, &version)
+                .await
+            {
+                self.completed("source", &key, Some(&why), &[]).await;
+                return Err(why);
+            }
 
-                // github::status(&client, org, &repo.name, &git.id, &ctx).await;
+            //     with debuild_lock:
+
+            let timeout = config.repo(&self.repo.name).source_timeout();
+            match debuild(git, &extract_dir, timeout).await {
+                Ok(()) => {
+                    info!(
+                        "{} commit {} on {}: finished building source",
+                        source_name, git.id, codename
+                    );
+
+                    let artifacts = [dsc_path.as_path().into(), tar_path.as_path().into()];
+                    self.completed("source", &key, None, &artifacts).await;
+                }
+                Err(why) => {
+                    let (error, log_path) =
+                        source_failure(&git.id, source_name, path_version, source_dir).await;
+
+                    self.completed("source", &key, Some(&error), &[log_path.into()])
+                        .await;
+                    return Err(error);
+                }
             }
+        }
 
-            let changelog_path = if is_linux {
-                extract_dir.join("debian.master/changelog")
+        ensure!(dsc_path.exists(), "missing dsc: {}", dsc_path.display());
+        ensure!(tar_path.exists(), "missing tar: {}", tar_path.display());
+
+        Ok((dsc_path, tar_path, path_version.into()))
+    }
+
+    /// The directory that sources are built in.
+    ///
+    /// Releases without a series suffix have the same version in every series, so that each
+    /// series has its own directory, rather than sharing packages with the same file names.
+    fn source_dir(&self) -> PathBuf {
+        if self.tagged && !self.config.releases.series_suffix {
+            self.config.dirs.source.join(self.codename)
+        } else {
+            self.config.dirs.source.clone()
+        }
+    }
+
+    /// The directory that binaries are built in, which is per series like [`Dpkg::source_dir`].
+    fn binary_dir(&self) -> PathBuf {
+        if self.tagged && !self.config.releases.series_suffix {
+            self.config.dirs.binary.join(self.codename)
+        } else {
+            self.config.dirs.binary.clone()
+        }
+    }
+
+    /// Appends the changelog entry and prepares the extracted source for debuild.
+    async fn prepare_source(
+        &self,
+        extract_dir: &Path,
+        source_name: &str,
+        version: &str,
+    ) -> anyhow::Result<()> {
+        let &Self {
+            config,
+            codename,
+            git,
+            ..
+        } = self;
+
+        let overrides = config.repo(&self.repo.name);
+        let patches_dir = extract_dir.join("debian/patches");
+        let changelog_path = extract_dir.join(overrides.changelog());
+
+        // Releases without a series suffix are built with their changelog as it is.
+        if !self.tagged || config.releases.series_suffix {
+            let changes = if self.tagged {
+                "* Release Build"
             } else {
-                debian_path.join("changelog")
+                "* Auto Build"
             };
 
             changelog_append(
@@ -363,7 +423,7 @@ impl<'a> Dpkg<'a> {
                     email: &config.email,
                     package: &source_name,
                     version: &version,
-                    changes: vec!["* Auto Build"],
+                    changes: vec![changes],
                     metadata: cascade! {
                         HashMap::new();
                         ..insert("urgency", "medium");
@@ -372,68 +432,207 @@ impl<'a> Dpkg<'a> {
             )
             .await
             .context("failed to append entry to changelog")?;
+        }
 
-            if patches_dir.exists() {
-                info!(
-                    "{} commit {} on {}: applying debian patches",
-                    source_name, git.id, codename
-                );
-                check_call("quilt", &["push", "-a"], Some(&extract_dir))
-                    .await
-                    .context("failed to push quilt patches")?;
-                info!(
-                    "{} commit {} on {}: finished applying debian patches",
-                    source_name, git.id, codename
-                );
-            }
+        if patches_dir.exists() {
+            info!(
+                "{} commit {} on {}: applying debian patches",
+                source_name, git.id, codename
+            );
+            check_call_captured("quilt", &["push", "-a"], Some(&extract_dir))
+                .await
+                .context("failed to push quilt patches")?;
+            info!(
+                "{} commit {} on {}: finished applying debian patches",
+                source_name, git.id, codename
+            );
+        }
 
-            if is_linux {
-                info!(
-                    "{} commit {} on {}: updating changelog",
-                    source_name, git.id, codename
-                );
-                check_call("fakeroot", &["debian/rules", "clean"], Some(&extract_dir))
-                    .await
-                    .context("failed to execute `fakeroot debian/rules clean`")?;
-                info!(
-                    "{} commit {} on {}: finished updating changelog",
-                    source_name, git.id, codename
+        for command in &overrides.pre_source {
+            let (cmd, args) = command
+                .split_first()
+                .context("pre-source command is empty")?;
+
+            let args = args.iter().map(|arg| arg.as_ref()).collect::<Vec<&str>>();
+            let command = command.join(" ");
+            info!(
+                "{} commit {} on {}: executing `{}`",
+                source_name, git.id, codename, command
+            );
+            check_call_captured(cmd, &args, Some(&extract_dir))
+                .await
+                .with_context(|| format!("failed to execute `{}`", command))?;
+            info!(
+                "{} commit {} on {}: finished executing `{}`",
+                source_name, git.id, codename, command
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Identifies a stage of this build in the build state, where the `source` arch is the source stage.
+    fn key<'b>(&'b self, arch: &'b str) -> BuildKey<'b> {
+        BuildKey {
+            repo: &self.repo.name,
+            commit: &self.git.id,
+            series: self.codename,
+            pocket: self.pocket,
+            arch,
+            stage: if arch == "source" {
+                state::Stage::Source
+            } else {
+                state::Stage::Binary
+            },
+        }
+    }
+
+    /// Whether this stage is blacklisted, which is the case after it has failed.
+    fn blacklisted(&self, key: &BuildKey) -> bool {
+        match self
+            .state
+            .is_blacklisted(key.repo, key.commit, key.series, key.arch)
+        {
+            Ok(blacklisted) => blacklisted,
+            Err(why) => {
+                error!(
+                    "{} commit {}: failed to query state: {}",
+                    key.repo, key.commit, why
                 );
+                false
             }
+        }
+    }
 
-            //     with debuild_lock:
+    /// Records artifacts that a previous run built, for stages without a record.
+    fn existing(&self, key: &BuildKey, artifacts: &[Box<Path>]) {
+        if let Err(why) = self.state.existing(key, artifacts) {
+            error!(
+                "{} commit {}: failed to record state: {}",
+                key.repo, key.commit, why
+            );
+        }
+    }
 
-            match debuild(git, &extract_dir).await {
-                Ok(()) => {
-                    info!(
-                        "{} commit {} on {}: finished building source",
-                        source_name, git.id, codename
-                    );
-                    //     github_status(name, git.id, series.codename + "/source", "success")
-                }
-                Err(why) => {
-                    let error =
-                        source_failure(&git.id, source_name, path_version, &config.dirs.source)
-                            .await;
+    /// Reports the start of a build stage.
+    async fn started(&self, stage: &str, key: &BuildKey<'_>) {
+        if let Err(why) = self.state.started(key) {
+            error!(
+                "{} commit {}: failed to record state: {}",
+                key.repo, key.commit, why
+            );
+        }
 
-                    //     try:
-                    //         github_status(name, git.id, series.codename + "/source", "failure")
-                    //     except Exception as ex_s:
-                    //         print("\x1B[1m{} commit {} on {}: failed to report build failure: {!r}\x1B[0m\n".format(source_name, git.id, series.codename, ex_s))
+        self.status(stage, "pending").await;
+    }
 
-                    return Err(error);
-                }
+    /// Reports the completion of a build stage, and the error that it failed with.
+    ///
+    /// For failures, the `artifacts` are the build logs that explain the failure.
+    /// Check runs are only created when they are enabled in the config.
+    async fn completed(
+        &self,
+        stage: &str,
+        key: &BuildKey<'_>,
+        failure: Option<&anyhow::Error>,
+        artifacts: &[Box<Path>],
+    ) {
+        let (state, status) = if failure.is_some() {
+            ("failure", Status::Failed)
+        } else {
+            ("success", Status::Succeeded)
+        };
+
+        let reason = failure.map(|why| why.to_string());
+        let reason = reason.as_ref().and_then(|reason| reason.lines().next());
+
+        if let Err(why) = self.state.finished(key, status, reason, artifacts) {
+            error!(
+                "{} commit {}: failed to record state: {}",
+                key.repo, key.commit, why
+            );
+        }
+
+        if failure.is_some() {
+            let expires = self
+                .config
+                .blacklist_ttl_hours
+                .map(|hours| Utc::now() + Duration::hours(hours as i64));
+
+            warn!(
+                "{} commit {} on {}: blacklisting {}",
+                key.repo, key.commit, key.series, key.arch
+            );
+
+            let result = self
+                .state
+                .blacklist_add(key.repo, key.commit, key.series, key.arch, reason, expires);
+
+            if let Err(why) = result {
+                error!(
+                    "{} commit {}: failed to blacklist: {}",
+                    key.repo, key.commit, why
+                );
             }
         }
 
-        ensure!(dsc_path.exists(), "missing dsc: {}", dsc_path.display());
-        ensure!(tar_path.exists(), "missing tar: {}", tar_path.display());
+        self.status(stage, state).await;
+
+        if let (true, Some(owner)) = (self.config.checks, self.owner) {
+            let name = [&self.config.context, "/", self.codename, "/", stage].concat();
+            checks::report(
+                self.client,
+                owner,
+                &self.repo.name,
+                &self.git.id,
+                &name,
+                self.config.build_url.as_ref().map(|url| url.as_ref()),
+                failure,
+            )
+            .await;
+        }
+    }
 
-        Ok((dsc_path, tar_path, path_version.into()))
+    /// Reports the state of a build stage as a commit status on the forge.
+    ///
+    /// Failing to report a status is logged, but is never treated as a build failure.
+    async fn status(&self, stage: &str, state: &str) {
+        let &Self {
+            config,
+            forge,
+            owner,
+            repo,
+            codename,
+            git,
+            ..
+        } = self;
+
+        let owner = match owner {
+            Some(owner) => owner,
+            None => return,
+        };
+
+        let context_ctx = [codename, "/", stage].concat();
+        let context = [&config.context, "/", &context_ctx].concat();
+        let description = [&config.description, " ", &context_ctx].concat();
+
+        let ctx = StatusContext {
+            context: &context,
+            description: &description,
+            state,
+            target_url: config.build_url.as_ref().map(|url| url.as_ref()),
+        };
+
+        if let Err(why) = forge.status(owner, &repo.name, &git.id, &ctx).await {
+            warn!(
+                "{} commit {} on {}: failed to report {} status for {}: {}",
+                repo.name, git.id, codename, state, context_ctx, why
+            );
+        }
     }
 }
 
-async fn debuild(git: &GitTar, extract_dir: &Path) -> io::Result<()> {
+async fn debuild(git: &GitTar, extract_dir: &Path, timeout: Option<StdDuration>) -> io::Result<()> {
     let source_date_epoch = ["SOURCE_DATE_EPOCH=", &git.timestamp.to_string()].concat();
     let args = &[
         "--preserve-envvar",
@@ -446,7 +645,7 @@ async fn debuild(git: &GitTar, extract_dir: &Path) -> io::Result<()> {
         "--source-option=--tar-ignore=.git",
     ];
 
-    check_call("debuild", args, Some(extract_dir)).await
+    check_call_timeout("debuild", args, Some(extract_dir), timeout).await
 }
 
 async fn source_failure(
@@ -454,14 +653,16 @@ async fn source_failure(
     source_name: &str,
     path_version: &str,
     source: &Path,
-) -> anyhow::Error {
+) -> (anyhow::Error, PathBuf) {
     let log_name = [source_name, "_", path_version, "_source.build"].concat();
     let log_path = source.join(&log_name);
 
-    match read_to_string(&log_path).await {
+    let error = match read_to_string(&log_path).await {
         Ok(log) => anyhow!("failed to build source:\n{}", log),
         Err(why) => anyhow!("failed to build source (log read failed)"),
-    }
+    };
+
+    (error, log_path)
 }
 
 fn parse_dsc<'a>(dsc: &'a str) -> anyhow::Result<(&'a str, &'a str, &'a str)> {
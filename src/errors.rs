@@ -1,6 +1,7 @@
 //! Generic error types shared across the project
 
-use std::{io, path::Path};
+use crate::STRING_BUF;
+use std::{error::Error as StdError, fmt::Write, io, path::Path};
 
 #[derive(Debug, Error)]
 pub enum DirError {
@@ -33,3 +34,21 @@ pub enum FileError {
         source: io::Error,
     },
 }
+
+/// Formats an error and each of its causes on their own lines, and passes them to `func`.
+pub fn format_error<F: FnOnce(&str)>(why: &(dyn StdError + 'static), func: F) {
+    STRING_BUF.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+
+        buffer.clear();
+        let _ = writeln!(buffer, "{}", why);
+
+        let mut cause = why.source();
+        while let Some(error) = cause {
+            let _ = writeln!(buffer, "    caused by: {}", error);
+            cause = error.source();
+        }
+
+        func(&buffer);
+    });
+}
@@ -1,65 +1,97 @@
 use crate::{
-    config::{Config, ConfigOrganization},
+    config::{BranchTarget, Config, ConfigOrganization, ConfigRemote, Visibility},
+    filter::Filters,
+    forge::{self, Forge},
     git,
-    github::{self, Branch as GitHubBranch, Repo},
+    github::{PullRequest, Repo},
+    state::State,
 };
 
 use futures::{
     prelude::*,
     stream::{FuturesUnordered, Stream},
 };
-use reqwest::Client;
-use std::{collections::HashMap, io, path::Path, rc::Rc, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("github")]
-    FetchRemote(Box<str>, #[source] github::Error),
-    #[error("failed to fetch repos from GitHub organization {}", _0)]
-    FetchOrgRepos(Box<str>, #[source] github::Error),
-    #[error("failed to checkout git branch for {}", _0)]
-    GitCheckout(Box<str>, #[source] io::Error),
+    #[error("failed to fetch branches of {}", _0)]
+    FetchRemote(Box<str>, #[source] forge::Error),
+    #[error("failed to fetch tags of {}", _0)]
+    FetchTags(Box<str>, #[source] forge::Error),
+    #[error("failed to fetch pull requests of {}", _0)]
+    FetchPulls(Box<str>, #[source] forge::Error),
+    #[error("failed to fetch repository {}", _0)]
+    FetchRepo(Box<str>, #[source] forge::Error),
+    #[error("failed to fetch repos from organization {}", _0)]
+    FetchOrgRepos(Box<str>, #[source] forge::Error),
     #[error("failed to clone {}", _0)]
     GitClone(Box<str>, #[source] io::Error),
     #[error("failed to fetch {}", _0)]
     GitFetch(Box<str>, #[source] io::Error),
-    #[error("failed to get status of branches for {}", _0)]
+    #[error("failed to list the refs of {}", _0)]
     GitStatus(Box<str>, #[source] io::Error),
+    #[error("failed to look up the tree of {}", _0)]
+    GitTree(Box<str>, #[source] io::Error),
 }
 
 #[derive(Debug)]
 pub struct Repository {
     // The repository which has been fetched.
     pub name: Box<str>,
-    /// The working directory of this repository.
+    /// The owner of this repository on the forge, which is `None` for plain git remotes.
+    pub owner: Option<Box<str>>,
+    /// The bare mirror of this repository, which commits are archived from.
     pub directory: Box<Path>,
     // Branches found in this repository
     pub branches: Box<[Branch]>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Branch {
     /// The branch which has been checked.
     pub name: Box<str>,
     /// The commit hash for this branch from the remote.
     pub sha: Box<str>,
-    /// If this branch was just checked out.
-    pub required_checkout: bool,
+    /// If this commit was not in the mirror before it was fetched.
+    #[serde(skip)]
+    pub updated: bool,
+    /// Where this branch is to be built.
+    pub target: BranchTarget,
+}
+
+/// A branch, tag, or pull request of a remote which is to be built, before it is fetched.
+struct Candidate {
+    name: Box<str>,
+    sha: Box<str>,
+    target: BranchTarget,
 }
 
-pub struct Fetcher<'a> {
-    client: &'a Arc<Client>,
+pub struct Fetcher<'a, F: Forge> {
+    forge: &'a F,
     config: &'a Config,
+    filters: &'a Filters,
+    state: &'a State,
 }
 
-impl<'a> Fetcher<'a> {
-    pub fn new(client: &'a Arc<Client>, config: &'a Config) -> Self {
-        Self { client, config }
+impl<'a, F: Forge> Fetcher<'a, F> {
+    pub fn new(forge: &'a F, config: &'a Config, filters: &'a Filters, state: &'a State) -> Self {
+        Self {
+            forge,
+            config,
+            filters,
+            state,
+        }
     }
 
     /// Fetches an organization's repositories asynchronously.
     pub async fn organization(&self, org: &str) -> Result<Vec<Repo>, Error> {
-        github::organization_repos(self.client.clone(), org)
+        self.forge
+            .organization_repos(org)
             .await
             .map_err(|why| Error::FetchOrgRepos(org.into(), why))
     }
@@ -72,73 +104,354 @@ impl<'a> Fetcher<'a> {
     ) -> impl Stream<Item = Result<Repository, Error>> + 'b {
         repos
             .into_iter()
-            .filter(|repo| repo_filter(org, repo))
-            .map(move |repo| self.branches(&org.name, repo))
+            .filter(move |repo| exclusion(org, self.filters, repo).is_none())
+            .map(move |repo| self.changed_branches(&org.name, repo))
             .collect::<FuturesUnordered<_>>()
     }
 
-    /// Fetches the branches of a repository concurrently
+    /// Fetches the individual GitHub repositories and remotes of the config concurrently.
+    pub fn standalone<'b>(&'b self) -> impl Stream<Item = Result<Repository, Error>> + 'b {
+        let github = self
+            .config
+            .github
+            .repos
+            .iter()
+            .filter_map(|full_name| {
+                let mut parts = full_name.splitn(2, '/');
+                match (parts.next(), parts.next()) {
+                    (Some(owner), Some(name)) => Some((owner, name)),
+                    _ => {
+                        error!("GitHub repository {} is not `owner/name`", full_name);
+                        None
+                    }
+                }
+            })
+            .filter(move |(_, name)| self.filters.repo(name))
+            .map(move |(owner, name)| self.changed_repository(owner, name))
+            .collect::<FuturesUnordered<_>>();
+
+        let remotes = self
+            .config
+            .remotes
+            .iter()
+            .filter(move |remote| self.filters.repo(&remote.name))
+            .map(move |remote| self.remote(remote))
+            .collect::<FuturesUnordered<_>>();
+
+        github.chain(remotes)
+    }
+
+    /// Fetches a single repository of the forge, and its branches.
+    pub async fn repository(&self, owner: &str, name: &str) -> Result<Repository, Error> {
+        let repo = self
+            .forge
+            .repository(owner, name)
+            .await
+            .map_err(|why| Error::FetchRepo([owner, "/", name].concat().into(), why))?;
+
+        self.branches(owner, &repo).await
+    }
+
+    /// Fetches a single repository of the forge, and its branches if it has changed.
+    async fn changed_repository(&self, owner: &str, name: &str) -> Result<Repository, Error> {
+        let repo = self
+            .forge
+            .repository(owner, name)
+            .await
+            .map_err(|why| Error::FetchRepo([owner, "/", name].concat().into(), why))?;
+
+        self.changed_branches(owner, &repo).await
+    }
+
+    /// Fetches the branches of a repository, unless it was not pushed to since they were last
+    /// fetched, in which case the branches of that fetch are reused without any git work.
+    async fn changed_branches(&self, user: &str, repo: &Repo) -> Result<Repository, Error> {
+        let cwd = self.mirror_dir(&repo.name);
+
+        if self.skips_unchanged() && cwd.exists() {
+            let unchanged =
+                self.state
+                    .unchanged_branches(user, &repo.name, repo.pushed_at, &self.config.rules);
+
+            match unchanged {
+                Ok(Some(branches)) => {
+                    info!(
+                        "{}/{}: reusing branches, as it was not pushed to since they were fetched",
+                        user, repo.name
+                    );
+
+                    return Ok(Repository {
+                        name: repo.name.clone(),
+                        owner: Some(user.into()),
+                        directory: cwd.into(),
+                        branches: branches.into(),
+                    });
+                }
+                Ok(None) => (),
+                Err(why) => error!("{}/{}: failed to query state: {}", user, repo.name, why),
+            }
+        }
+
+        self.branches(user, repo).await
+    }
+
+    /// Fetches the branches of a repository on the forge concurrently
     pub async fn branches<'b>(
         &'b self,
         user: &'b str,
         repo: &'b Repo,
     ) -> Result<Repository, Error> {
-        let Self { client, config } = *self;
-        let cwd = config.dirs.base.join(&*repo.name);
+        let Self { forge, config, .. } = *self;
+        let cwd = self.mirror_dir(&repo.name);
+        let url = forge.clone_url(user, &repo.name);
 
         let remote_branches = async {
-            fetch_remote_branches(client.clone(), user, &*repo.name)
+            forge
+                .repository_branches(user, &*repo.name)
                 .await
+                .map(|branches| {
+                    branches
+                        .into_iter()
+                        .map(|branch| (branch.name, branch.commit.sha))
+                        .collect::<Vec<_>>()
+                })
                 .map_err(|why| Error::FetchRemote(repo.name.clone(), why))
         };
 
-        let local_branches = fetch_local_branches(&config.dirs.base, &cwd, user, &*repo.name);
+        let tags = async {
+            if config.releases.tags.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            forge
+                .repository_tags(user, &*repo.name)
+                .await
+                .map(|tags| {
+                    tags.into_iter()
+                        .map(|tag| (tag.name, tag.commit.sha))
+                        .collect::<Vec<_>>()
+                })
+                .map_err(|why| Error::FetchTags(repo.name.clone(), why))
+        };
+
+        let pull_requests = async {
+            if !config.pull_requests.build {
+                return Ok(Vec::new());
+            }
+
+            forge
+                .pull_requests(user, &*repo.name)
+                .await
+                .map_err(|why| Error::FetchPulls(repo.name.clone(), why))
+        };
+
+        let known = mirror_ids(&cwd, &repo.name, &url);
 
         info!(
             "fetching local and remote branches for {}/{}",
             user, repo.name
         );
-        let (remote_branches, local_branches) = try_join!(remote_branches, local_branches)?;
+        let (remote_branches, tags, pull_requests, known) =
+            try_join!(remote_branches, tags, pull_requests, known)?;
         info!(
             "fetched local and remote branches for {}/{}",
             user, repo.name
         );
 
-        let mut branches = Vec::new();
+        let mut candidates = self.branch_candidates(&repo.name, remote_branches);
+        candidates.extend(self.tag_candidates(&repo.name, tags));
+        candidates.extend(self.pull_request_candidates(&repo.name, pull_requests));
 
-        // NOTE: This must be executed serially, rather than concurrently.
-        //       Concurrent executions of git in the same directory causes
-        //       git to get into an inconsistent state.
-        let mut fetched = false;
-        for branch in remote_branches {
-            let required_checkout = local_branches
-                .get(&branch.name)
-                .map_or(true, |commit| commit != &branch.commit.sha);
-
-            if required_checkout {
-                if !fetched {
-                    fetched = true;
-                    info!("fetching on {}", repo.name);
-                    if let Err(why) = git::fetch(&cwd, "origin").await {
-                        let repo = [&repo.name, "/", &branch.name].concat();
-                        let error = Error::GitFetch(repo.into(), why);
-                        return Err(error);
-                    }
+        let repository = self
+            .resolve(Some(user), &repo.name, &cwd, candidates, known, false)
+            .await?;
+
+        if self.skips_unchanged() {
+            let result = self.state.set_branches(
+                user,
+                &repo.name,
+                repo.pushed_at,
+                &self.config.rules,
+                &repository.branches,
+            );
+
+            if let Err(why) = result {
+                error!("{}/{}: failed to record state: {}", user, repo.name, why);
+            }
+        }
+
+        Ok(repository)
+    }
+
+    /// Fetches the branches of a repository outside of GitHub, using `git ls-remote`.
+    pub async fn remote(&self, remote: &ConfigRemote) -> Result<Repository, Error> {
+        let cwd = self.mirror_dir(&remote.name);
+
+        info!("fetching local and remote branches for {}", remote.url);
+        let known = mirror_ids(&cwd, &remote.name, &remote.url).await?;
+
+        let mut ids = HashMap::new();
+        git::ids_and_branches(&mut ids, &cwd)
+            .await
+            .map_err(|why| Error::GitFetch(remote.url.clone(), why))?;
+        info!("fetched local and remote branches for {}", remote.url);
+
+        let remote_branches = ids
+            .into_iter()
+            .flat_map(|(id, branches)| branches.into_iter().map(move |branch| (branch, id.clone())))
+            .collect();
+
+        let mut candidates = self.branch_candidates(&remote.name, remote_branches);
+
+        if !self.config.releases.tags.is_empty() {
+            let tags = git::remote_tags(&cwd)
+                .await
+                .map_err(|why| Error::GitFetch(remote.url.clone(), why))?;
+
+            candidates.extend(self.tag_candidates(&remote.name, tags));
+        }
+
+        self.resolve(None, &remote.name, &cwd, candidates, known, true)
+            .await
+    }
+
+    /// Selects the branches which the filters accept, and which a branch rule builds.
+    fn branch_candidates(&self, name: &str, branches: Vec<(Box<str>, Box<str>)>) -> Vec<Candidate> {
+        let Self {
+            config, filters, ..
+        } = *self;
+
+        branches
+            .into_iter()
+            .filter(|(branch, _)| filters.branch(branch))
+            .filter_map(|(branch, sha)| match config.branches.map(&branch) {
+                Some(target) => Some(Candidate {
+                    name: branch,
+                    sha,
+                    target,
+                }),
+                None => {
+                    info!(
+                        "{} branch {}: skipping because no branch rule builds it",
+                        name, branch
+                    );
+                    None
                 }
+            })
+            .collect()
+    }
+
+    /// Selects the tags which the filters accept, and which a release rule builds.
+    fn tag_candidates(&self, name: &str, tags: Vec<(Box<str>, Box<str>)>) -> Vec<Candidate> {
+        let Self {
+            config, filters, ..
+        } = *self;
 
-                info!("checking out {}: {}", repo.name, branch.name);
-                if let Err(why) = git::checkout_id(&cwd, &branch.commit.sha).await {
-                    let repo = [&repo.name, "/", &branch.name].concat();
-                    let error = Error::GitCheckout(repo.into(), why);
-                    return Err(error);
+        tags.into_iter()
+            .filter(|(tag, _)| filters.branch(tag))
+            .filter_map(|(tag, sha)| {
+                let target = config.releases.tags.map(&tag)?;
+
+                Some(Candidate {
+                    name: ["tags/", &tag].concat().into(),
+                    sha,
+                    target: BranchTarget {
+                        release: true,
+                        ..target
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Selects the pull requests whose base branch is built, which are built for the series
+    /// of their base branch, in the pocket of the pull request.
+    fn pull_request_candidates(&self, name: &str, pulls: Vec<PullRequest>) -> Vec<Candidate> {
+        let Self {
+            config, filters, ..
+        } = *self;
+
+        pulls
+            .into_iter()
+            .filter(|pull| filters.branch(&pull.base.reference))
+            .filter_map(|pull| match config.branches.map(&pull.base.reference) {
+                Some(base) => Some(Candidate {
+                    name: ["pull/", &pull.number.to_string()].concat().into(),
+                    sha: pull.head.sha,
+                    target: BranchTarget {
+                        pocket: config.pull_requests.pocket(pull.number),
+                        series: base.series,
+                        release: false,
+                    },
+                }),
+                None => {
+                    info!(
+                        "{} pull request {}: skipping because no branch rule builds {}",
+                        name, pull.number, pull.base.reference
+                    );
+                    None
                 }
-                info!("checked out {}: {}", repo.name, branch.name);
+            })
+            .collect()
+    }
+
+    /// Fetches the mirror if it lacks the commit of a candidate, and selects the candidates
+    /// whose commits have a debian directory.
+    ///
+    /// Commits are archived from the mirror rather than checked out, so every candidate is
+    /// inspected concurrently once the mirror has been fetched.
+    async fn resolve(
+        &self,
+        owner: Option<&str>,
+        name: &str,
+        cwd: &Path,
+        candidates: Vec<Candidate>,
+        known: HashSet<Box<str>>,
+        fetched: bool,
+    ) -> Result<Repository, Error> {
+        let missing = candidates
+            .iter()
+            .any(|candidate| !known.contains(&candidate.sha));
+
+        if missing && !fetched {
+            info!("fetching on {}", name);
+            git::fetch(cwd, "origin")
+                .await
+                .map_err(|why| Error::GitFetch(name.into(), why))?;
+        }
+
+        let checks = candidates
+            .into_iter()
+            .map(|candidate| async move {
+                let has_debian = self.has_debian(cwd, name, &candidate.sha).await?;
+                Ok::<_, Error>((candidate, has_debian))
+            })
+            .collect::<FuturesUnordered<_>>()
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let mut branches = Vec::new();
+
+        for (candidate, has_debian) in checks {
+            let Candidate {
+                name: branch,
+                sha,
+                target,
+            } = candidate;
+
+            if !has_debian {
+                info!(
+                    "{} branch {}: skipping commit {} because it has no debian directory",
+                    name, branch, sha
+                );
+                continue;
             }
 
             branches.push(Branch {
-                name: branch.name,
-                sha: branch.commit.sha,
-                required_checkout,
+                name: branch,
+                updated: !known.contains(&sha),
+                sha,
+                target,
             });
         }
 
@@ -146,64 +459,110 @@ impl<'a> Fetcher<'a> {
 
         Ok(Repository {
             directory: cwd.into(),
-            name: repo.name.clone(),
+            name: name.into(),
+            owner: owner.map(Box::from),
             branches: branches.into(),
         })
     }
+
+    /// The bare mirror of a repository.
+    fn mirror_dir(&self, name: &str) -> PathBuf {
+        self.config.dirs.mirror.join([name, ".git"].concat())
+    }
+
+    /// Unchanged repositories are only skipped when every branch is fetched, so that the
+    /// branches which are recorded for them are complete.
+    ///
+    /// Opening a pull request does not change when its base repository was pushed to, so
+    /// nothing is skipped when pull requests are built.
+    fn skips_unchanged(&self) -> bool {
+        self.config.github.skip_unchanged
+            && !self.config.pull_requests.build
+            && self.filters.branches.is_empty()
+    }
+
+    /// Checks if a commit has a debian directory, remembering the answer for future runs.
+    async fn has_debian(&self, cwd: &Path, repo: &str, sha: &str) -> Result<bool, Error> {
+        match self.state.has_debian(repo, sha) {
+            Ok(Some(has_debian)) => return Ok(has_debian),
+            Ok(None) => (),
+            Err(why) => error!("{} commit {}: failed to query state: {}", repo, sha, why),
+        }
+
+        let has_debian = git::has_dir(cwd, sha, "debian")
+            .await
+            .map_err(|why| Error::GitTree([repo, "@", sha].concat().into(), why))?;
+
+        if let Err(why) = self.state.set_has_debian(repo, sha, has_debian) {
+            error!("{} commit {}: failed to record state: {}", repo, sha, why);
+        }
+
+        Ok(has_debian)
+    }
 }
 
-async fn fetch_local_branches(
-    parent_cwd: &Path,
-    cwd: &Path,
-    org: &str,
-    repo: &str,
-) -> Result<HashMap<Box<str>, Box<str>>, Error> {
+/// Creates the mirror of a repository if it does not exist, and lists the commits of its refs.
+async fn mirror_ids(cwd: &Path, name: &str, url: &str) -> Result<HashSet<Box<str>>, Error> {
     if !cwd.exists() {
-        info!("cloning {}/{}", org, repo);
-        let url = ["https://github.com/", org, "/", repo].concat();
-        git::clone(parent_cwd, &url)
+        info!("mirroring {}", url);
+        git::mirror(url, cwd)
             .await
-            .map_err(|why| Error::GitClone([org, "/", repo].concat().into(), why))?;
-        info!("cloned {}/{}", org, repo);
+            .map_err(|why| Error::GitClone(url.into(), why))?;
+        info!("mirrored {}", url);
     }
 
-    git::local_branch_and_ids(&cwd)
+    git::ref_ids(cwd)
         .await
-        .map_err(|why| Error::GitStatus([org, "/", repo].concat().into(), why))
+        .map_err(|why| Error::GitStatus(name.into(), why))
 }
 
-async fn fetch_remote_branches(
-    client: Arc<Client>,
-    org: &str,
-    repo: &str,
-) -> Result<Vec<GitHubBranch>, github::Error> {
-    let mut branches = github::repository_branches(client, org, repo).await;
-
-    // Filter `_nobuild` branches.
-    if let Ok(branches) = branches.as_mut() {
-        let mut remove = Vec::new();
+/// Why a repository of an organization is not selected, or `None` if it is selected.
+pub fn exclusion(org: &ConfigOrganization, filters: &Filters, repo: &Repo) -> Option<String> {
+    if !filters.repo(&repo.name) {
+        return Some("not accepted by --repo".into());
+    }
 
-        for (id, branch) in branches.iter().enumerate() {
-            if branch.name.contains("/") {
-                remove.push(id);
-            }
+    if let Some(prefix) = org.starts_filter.as_ref() {
+        if !repo.name.starts_with(&**prefix) {
+            return Some(["name does not start with ", prefix].concat());
         }
+    }
 
-        for id in remove.into_iter().rev() {
-            branches.swap_remove(id);
-        }
+    if !org.include.is_empty() && !org.include.iter().any(|regex| regex.is_match(&repo.name)) {
+        return Some("name does not match an include regex".into());
     }
 
-    branches
-}
+    if let Some(regex) = org.exclude.iter().find(|regex| regex.is_match(&repo.name)) {
+        return Some(["name matches exclude regex ", regex.as_str()].concat());
+    }
 
-/// Filter repositories which meet filtering criteria.
-fn repo_filter(org: &ConfigOrganization, repo: &Repo) -> bool {
-    if let Some(needle) = org.starts_filter.as_ref() {
-        if repo.name.starts_with(needle.as_ref()) {
-            return false;
-        }
+    if !org.topics.is_empty() && !repo.topics.iter().any(|topic| org.topics.contains(topic)) {
+        return Some("has none of the selected topics".into());
     }
 
-    true
+    if let Some(topic) = repo
+        .topics
+        .iter()
+        .find(|topic| org.exclude_topics.contains(topic))
+    {
+        return Some(["has excluded topic ", topic].concat());
+    }
+
+    match org.archived {
+        Some(true) if !repo.archived => return Some("is not archived".into()),
+        Some(false) if repo.archived => return Some("is archived".into()),
+        _ => (),
+    }
+
+    match org.fork {
+        Some(true) if !repo.fork => return Some("is not a fork".into()),
+        Some(false) if repo.fork => return Some("is a fork".into()),
+        _ => (),
+    }
+
+    match org.visibility {
+        Some(Visibility::Public) if repo.private => Some("is private".into()),
+        Some(Visibility::Private) if !repo.private => Some("is public".into()),
+        _ => None,
+    }
 }
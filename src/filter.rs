@@ -0,0 +1,39 @@
+//! Operator-supplied filters which narrow down what a pipeline run touches.
+
+/// Restricts a run to a subset of repositories, branches, series, pockets, and archs.
+///
+/// An empty list for any field means that everything is accepted.
+#[derive(Debug, Default, Clone)]
+pub struct Filters {
+    pub repos: Vec<Box<str>>,
+    pub branches: Vec<Box<str>>,
+    pub series: Vec<Box<str>>,
+    pub pockets: Vec<Box<str>>,
+    pub archs: Vec<Box<str>>,
+}
+
+impl Filters {
+    pub fn repo(&self, name: &str) -> bool {
+        accepts(&self.repos, name)
+    }
+
+    pub fn branch(&self, name: &str) -> bool {
+        accepts(&self.branches, name)
+    }
+
+    pub fn series(&self, name: &str) -> bool {
+        accepts(&self.series, name)
+    }
+
+    pub fn pocket(&self, name: &str) -> bool {
+        accepts(&self.pockets, name)
+    }
+
+    pub fn arch(&self, name: &str) -> bool {
+        accepts(&self.archs, name)
+    }
+}
+
+fn accepts(list: &[Box<str>], value: &str) -> bool {
+    list.is_empty() || list.iter().any(|entry| &**entry == value)
+}
@@ -0,0 +1,175 @@
+//! Repositories served from a local directory, for running the pipeline without a network.
+//!
+//! The directory is laid out as:
+//!
+//! - `<org>.json`: the repositories of an organization, in the format of the GitHub API
+//! - `<owner>/<repo>/`: a git repository, whose local branches and tags are the repository's
+//!   branches and tags
+//! - `<owner>/<repo>.pulls.json`: the open pull requests of a repository, in the format of the
+//!   GitHub API, whose heads are the `refs/pull/<number>/head` refs of its git repository
+//! - `statuses.jsonl`: statuses reported for commits, which are appended as JSON lines
+
+use super::{Error, Forge};
+use crate::{
+    github::{Branch, Commit, PullRequest, Repo, StatusContext, Tag},
+    misc::check_output,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+pub struct Fixture {
+    root: PathBuf,
+}
+
+#[derive(Serialize)]
+struct FixtureStatus<'a> {
+    owner: &'a str,
+    repo: &'a str,
+    id: &'a str,
+    #[serde(flatten)]
+    context: &'a StatusContext<'a>,
+}
+
+impl Fixture {
+    /// Serves the fixtures in a directory, which should be an absolute path.
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn repo_dir(&self, owner: &str, repo: &str) -> PathBuf {
+        self.root.join(owner).join(repo)
+    }
+}
+
+#[async_trait]
+impl Forge for Fixture {
+    async fn organization_repos(&self, org: &str) -> Result<Vec<Repo>, Error> {
+        let path = self.root.join([org, ".json"].concat());
+
+        let json = fs::read_to_string(&path)
+            .map_err(|why| Error::FixtureRead(path.as_path().into(), why))?;
+
+        serde_json::from_str(&json).map_err(|why| Error::FixtureParse(path.into(), why))
+    }
+
+    async fn repository(&self, owner: &str, repo: &str) -> Result<Repo, Error> {
+        self.organization_repos(owner)
+            .await?
+            .into_iter()
+            .find(|found| &*found.name == repo)
+            .ok_or_else(|| Error::FixtureNotFound([owner, "/", repo].concat().into()))
+    }
+
+    async fn repository_branches(&self, owner: &str, repo: &str) -> Result<Vec<Branch>, Error> {
+        let cwd = self.repo_dir(owner, repo);
+
+        let output = check_output(
+            "git",
+            &[
+                "for-each-ref",
+                "--format=%(refname:lstrip=2) %(objectname)",
+                "refs/heads",
+            ],
+            Some(&cwd),
+        )
+        .await
+        .map_err(|why| Error::FixtureBranches(cwd.as_path().into(), why))?;
+
+        let branches = output
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let name = fields.next()?;
+                let sha = fields.next()?;
+
+                Some(Branch {
+                    name: name.into(),
+                    commit: Commit { sha: sha.into() },
+                })
+            })
+            .collect();
+
+        Ok(branches)
+    }
+
+    async fn repository_tags(&self, owner: &str, repo: &str) -> Result<Vec<Tag>, Error> {
+        let cwd = self.repo_dir(owner, repo);
+
+        // Annotated tags are peeled to the commit that they point to.
+        let output = check_output(
+            "git",
+            &[
+                "for-each-ref",
+                "--format=%(refname:lstrip=2) %(objectname) %(*objectname)",
+                "refs/tags",
+            ],
+            Some(&cwd),
+        )
+        .await
+        .map_err(|why| Error::FixtureTags(cwd.as_path().into(), why))?;
+
+        let tags = output
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let name = fields.next()?;
+                let object = fields.next()?;
+                let sha = fields.next().unwrap_or(object);
+
+                Some(Tag {
+                    name: name.into(),
+                    commit: Commit { sha: sha.into() },
+                })
+            })
+            .collect();
+
+        Ok(tags)
+    }
+
+    async fn pull_requests(&self, owner: &str, repo: &str) -> Result<Vec<PullRequest>, Error> {
+        let path = self.root.join(owner).join([repo, ".pulls.json"].concat());
+
+        // Repositories without a file have no open pull requests.
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(why) => return Err(Error::FixturePulls(path.into(), why)),
+        };
+
+        serde_json::from_str(&json).map_err(|why| Error::FixtureParse(path.into(), why))
+    }
+
+    async fn status(
+        &self,
+        owner: &str,
+        repo: &str,
+        id: &str,
+        context: &StatusContext<'_>,
+    ) -> Result<(), Error> {
+        let path = self.root.join("statuses.jsonl");
+
+        let status = FixtureStatus {
+            owner,
+            repo,
+            id,
+            context,
+        };
+
+        let mut line = serde_json::to_string(&status).expect("status is not serializable");
+        line.push('\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .map_err(|why| Error::FixtureStatus(path.into(), why))
+    }
+
+    fn clone_url(&self, owner: &str, repo: &str) -> String {
+        ["file://", &*self.repo_dir(owner, repo).to_string_lossy()].concat()
+    }
+}
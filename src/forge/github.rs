@@ -0,0 +1,57 @@
+use super::{Error, Forge};
+use crate::github::{self, Branch, PullRequest, Repo, StatusContext, Tag};
+use reqwest::Client;
+use std::{path::Path, sync::Arc};
+
+/// Repositories hosted on GitHub, accessed through its API.
+pub struct GitHub {
+    client: Arc<Client>,
+    /// Where responses are cached for conditional requests.
+    cache: Box<Path>,
+}
+
+impl GitHub {
+    pub fn new(client: Arc<Client>, cache: &Path) -> Self {
+        Self {
+            client,
+            cache: cache.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Forge for GitHub {
+    async fn organization_repos(&self, org: &str) -> Result<Vec<Repo>, Error> {
+        Ok(github::organization_repos(self.client.clone(), &self.cache, org).await?)
+    }
+
+    async fn repository(&self, owner: &str, repo: &str) -> Result<Repo, Error> {
+        Ok(github::repository(self.client.clone(), &self.cache, owner, repo).await?)
+    }
+
+    async fn repository_branches(&self, owner: &str, repo: &str) -> Result<Vec<Branch>, Error> {
+        Ok(github::repository_branches(self.client.clone(), &self.cache, owner, repo).await?)
+    }
+
+    async fn repository_tags(&self, owner: &str, repo: &str) -> Result<Vec<Tag>, Error> {
+        Ok(github::repository_tags(self.client.clone(), &self.cache, owner, repo).await?)
+    }
+
+    async fn pull_requests(&self, owner: &str, repo: &str) -> Result<Vec<PullRequest>, Error> {
+        Ok(github::repository_pulls(self.client.clone(), &self.cache, owner, repo).await?)
+    }
+
+    async fn status(
+        &self,
+        owner: &str,
+        repo: &str,
+        id: &str,
+        context: &StatusContext<'_>,
+    ) -> Result<(), Error> {
+        Ok(github::status(&self.client, owner, repo, id, context).await?)
+    }
+
+    fn clone_url(&self, owner: &str, repo: &str) -> String {
+        ["https://github.com/", owner, "/", repo].concat()
+    }
+}
@@ -0,0 +1,60 @@
+//! Hosts of git repositories, from which repositories and branches are fetched, and to which
+//! build statuses are reported.
+
+mod fixture;
+mod github;
+
+pub use self::{fixture::Fixture, github::GitHub};
+
+use crate::github::{Branch, PullRequest, Repo, StatusContext, Tag};
+use std::{io, path::Path};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("GitHub request failed")]
+    GitHub(#[from] crate::github::Error),
+    #[error("failed to read fixture at {:?}", _0)]
+    FixtureRead(Box<Path>, #[source] io::Error),
+    #[error("failed to parse fixture at {:?}", _0)]
+    FixtureParse(Box<Path>, #[source] serde_json::Error),
+    #[error("failed to list branches of fixture at {:?}", _0)]
+    FixtureBranches(Box<Path>, #[source] io::Error),
+    #[error("fixture repository {} was not found", _0)]
+    FixtureNotFound(Box<str>),
+    #[error("failed to list tags of fixture at {:?}", _0)]
+    FixtureTags(Box<Path>, #[source] io::Error),
+    #[error("failed to read pull requests of fixture at {:?}", _0)]
+    FixturePulls(Box<Path>, #[source] io::Error),
+    #[error("failed to record status in {:?}", _0)]
+    FixtureStatus(Box<Path>, #[source] io::Error),
+}
+
+#[async_trait]
+pub trait Forge: Send + Sync {
+    /// Lists the repositories of an organization.
+    async fn organization_repos(&self, org: &str) -> Result<Vec<Repo>, Error>;
+
+    /// Fetches a single repository.
+    async fn repository(&self, owner: &str, repo: &str) -> Result<Repo, Error>;
+
+    /// Lists the branches of a repository, and the commits that they point to.
+    async fn repository_branches(&self, owner: &str, repo: &str) -> Result<Vec<Branch>, Error>;
+
+    /// Lists the tags of a repository, and the commits that they point to.
+    async fn repository_tags(&self, owner: &str, repo: &str) -> Result<Vec<Tag>, Error>;
+
+    /// Lists the open pull requests of a repository.
+    async fn pull_requests(&self, owner: &str, repo: &str) -> Result<Vec<PullRequest>, Error>;
+
+    /// Sets the status of a commit.
+    async fn status(
+        &self,
+        owner: &str,
+        repo: &str,
+        id: &str,
+        context: &StatusContext<'_>,
+    ) -> Result<(), Error>;
+
+    /// The URL that a repository is cloned from.
+    fn clone_url(&self, owner: &str, repo: &str) -> String;
+}
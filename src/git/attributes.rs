@@ -0,0 +1,251 @@
+//! Attributes that `.gitattributes` files assign to paths, which change how they are archived.
+//!
+//! Attributes are read from the tree being archived, as `git archive` reads them. Each
+//! directory's `.gitattributes` takes precedence over those of its parents, and later lines
+//! take precedence over earlier ones.
+
+use regex::Regex;
+use std::path::Path;
+
+/// Attributes which change how a path is archived.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Attribute {
+    /// `export-ignore`, which leaves a path out of archives.
+    ExportIgnore,
+    /// `export-subst`, which expands the `$Format:<format>$` placeholders of a file.
+    ExportSubst,
+    /// `filter=lfs`, which stores the content of a file in Git LFS.
+    Lfs,
+}
+
+/// The rules of the `.gitattributes` files of the directories being archived.
+#[derive(Default)]
+pub struct Attributes {
+    rules: Vec<(Regex, Attribute, bool)>,
+}
+
+impl Attributes {
+    pub fn count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Adds the rules of the `.gitattributes` of a directory, which take precedence over those
+    /// of its parents.
+    pub fn push(&mut self, dir: &Path, content: &str) {
+        for line in content.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with("[attr]") {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let pattern = match fields.next() {
+                // Negative patterns are forbidden in `.gitattributes`.
+                Some(pattern) if !pattern.starts_with('!') => pattern,
+                _ => continue,
+            };
+
+            let attributes = fields.filter_map(parse_attribute).collect::<Vec<_>>();
+            if attributes.is_empty() {
+                continue;
+            }
+
+            if let Some(regex) = pattern_regex(dir, pattern) {
+                for (attribute, set) in attributes {
+                    self.rules.push((regex.clone(), attribute, set));
+                }
+            }
+        }
+    }
+
+    /// Removes the rules of directories which have been left.
+    pub fn truncate(&mut self, len: usize) {
+        self.rules.truncate(len);
+    }
+
+    /// Checks if the last rule of an attribute which matches a path sets it.
+    pub fn is_set(&self, path: &Path, attribute: Attribute) -> bool {
+        let path = path.to_string_lossy();
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|(regex, rule, _)| *rule == attribute && regex.is_match(&path))
+            .map_or(false, |&(_, _, set)| set)
+    }
+}
+
+/// Parses an attribute of a line, which is unset by a `-` or `!` prefix.
+fn parse_attribute(field: &str) -> Option<(Attribute, bool)> {
+    let (name, set) = if field.starts_with('-') || field.starts_with('!') {
+        (&field[1..], false)
+    } else {
+        (field, true)
+    };
+
+    match name {
+        "export-ignore" => Some((Attribute::ExportIgnore, set)),
+        "export-subst" => Some((Attribute::ExportSubst, set)),
+        "filter=lfs" => Some((Attribute::Lfs, set)),
+        // Any other filter replaces the `lfs` filter.
+        "filter" => Some((Attribute::Lfs, false)),
+        _ if name.starts_with("filter=") => Some((Attribute::Lfs, false)),
+        _ => None,
+    }
+}
+
+/// Translates a pattern of a `.gitattributes` in `dir` into a regex of paths in the repository.
+///
+/// Patterns without a slash match the names of files at any depth, like those of `.gitignore`.
+fn pattern_regex(dir: &Path, pattern: &str) -> Option<Regex> {
+    let anchored = pattern.contains('/');
+    let mut chars = pattern.trim_start_matches('/').chars().peekable();
+
+    let mut regex = String::from("^");
+
+    let dir = dir.to_string_lossy();
+    if !dir.is_empty() {
+        regex.push_str(&regex::escape(&dir));
+        regex.push('/');
+    }
+
+    if !anchored {
+        regex.push_str("(?:.*/)?");
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+
+                for c in &mut chars {
+                    if c == ']' {
+                        break;
+                    }
+
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+
+                    regex.push(c);
+                }
+
+                regex.push(']');
+            }
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4])));
+                }
+            }
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    regex.push('$');
+    Regex::new(&regex).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(dir: &str, pattern: &str, path: &str) -> bool {
+        pattern_regex(Path::new(dir), pattern)
+            .expect("invalid pattern")
+            .is_match(path)
+    }
+
+    #[test]
+    fn unanchored_patterns() {
+        assert!(matches("", "*.png", "logo.png"));
+        assert!(matches("", "*.png", "data/icons/logo.png"));
+        assert!(!matches("", "*.png", "logo.png.txt"));
+        assert!(matches("", "?.txt", "a.txt"));
+        assert!(!matches("", "?.txt", "ab.txt"));
+
+        // Patterns of a subdirectory only match within it.
+        assert!(matches("assets", "*.png", "assets/logo.png"));
+        assert!(matches("assets", "*.png", "assets/icons/logo.png"));
+        assert!(!matches("assets", "*.png", "logo.png"));
+    }
+
+    #[test]
+    fn anchored_patterns() {
+        assert!(matches("", "/docs/*.md", "docs/index.md"));
+        assert!(!matches("", "/docs/*.md", "docs/api/index.md"));
+        assert!(!matches("", "/docs/*.md", "src/docs/index.md"));
+        assert!(matches("", "docs/*.md", "docs/index.md"));
+        assert!(!matches("", "docs/*.md", "src/docs/index.md"));
+        assert!(matches("src", "/main.rs", "src/main.rs"));
+        assert!(!matches("src", "/main.rs", "main.rs"));
+    }
+
+    #[test]
+    fn double_star_patterns() {
+        assert!(matches("", "**/build", "build"));
+        assert!(matches("", "**/build", "a/b/build"));
+        assert!(!matches("", "**/build", "a/rebuild"));
+        assert!(matches("", "vendor/**", "vendor/a/b.c"));
+        assert!(!matches("", "vendor/**", "src/vendor/a.c"));
+        assert!(matches("", "a/**/b", "a/b"));
+        assert!(matches("", "a/**/b", "a/x/y/b"));
+    }
+
+    #[test]
+    fn bracket_patterns() {
+        assert!(matches("", "[abc].txt", "b.txt"));
+        assert!(!matches("", "[abc].txt", "d.txt"));
+        assert!(matches("", "[!abc].txt", "d.txt"));
+        assert!(!matches("", "[!abc].txt", "a.txt"));
+        assert!(matches("", "[0-9]*.log", "1.log"));
+        assert!(matches("", "\\*.txt", "*.txt"));
+        assert!(!matches("", "\\*.txt", "a.txt"));
+    }
+
+    #[test]
+    fn later_rules_take_precedence() {
+        let mut attributes = Attributes::default();
+        attributes.push(
+            Path::new(""),
+            "# comment\n\
+             [attr]binary -diff -merge -text\n\
+             *.bin filter=lfs diff=lfs merge=lfs -text\n\
+             *.txt export-ignore\n\
+             keep.txt -export-ignore\n\
+             VERSION export-subst\n\
+             !negated export-ignore\n",
+        );
+
+        assert!(attributes.is_set(Path::new("data/a.bin"), Attribute::Lfs));
+        assert!(!attributes.is_set(Path::new("data/a.bin"), Attribute::ExportIgnore));
+        assert!(attributes.is_set(Path::new("notes.txt"), Attribute::ExportIgnore));
+        assert!(!attributes.is_set(Path::new("keep.txt"), Attribute::ExportIgnore));
+        assert!(attributes.is_set(Path::new("VERSION"), Attribute::ExportSubst));
+        assert!(!attributes.is_set(Path::new("negated"), Attribute::ExportIgnore));
+
+        // Subdirectories override their parents, until they are left.
+        let inherited = attributes.count();
+        attributes.push(Path::new("vendor"), "*.bin filter=git-crypt\n");
+        assert!(!attributes.is_set(Path::new("vendor/a.bin"), Attribute::Lfs));
+        assert!(attributes.is_set(Path::new("a.bin"), Attribute::Lfs));
+
+        attributes.truncate(inherited);
+        assert!(attributes.is_set(Path::new("vendor/a.bin"), Attribute::Lfs));
+    }
+}
@@ -0,0 +1,527 @@
+//! Git LFS objects, which are committed as pointer files, and stored outside of the repository.
+//!
+//! Objects are read from the LFS store of a mirror, at `lfs/objects`, as `git lfs` lays it out.
+//! Objects which are not in the store are downloaded into it with the batch API of the LFS
+//! server, and verified against the size and hash of their pointer.
+
+use reqwest::Client;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str,
+};
+
+const POINTER_VERSIONS: &[&str] = &[
+    "https://git-lfs.github.com/spec/v1",
+    "https://hawser.github.com/spec/v1",
+];
+
+/// Pointer files are never larger than this, so larger files are never pointers.
+const POINTER_MAX_SIZE: usize = 1024;
+
+const MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+/// The pointer file of an LFS object.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Pointer {
+    pub oid: Box<str>,
+    pub size: u64,
+}
+
+impl Pointer {
+    /// Parses the content of a blob, which is not a pointer if it does not have a valid oid.
+    pub fn parse(content: &[u8]) -> Option<Self> {
+        if content.len() > POINTER_MAX_SIZE {
+            return None;
+        }
+
+        let mut lines = str::from_utf8(content).ok()?.lines();
+
+        let version = lines.next()?;
+        if !version.starts_with("version ")
+            || !POINTER_VERSIONS.contains(&&version["version ".len()..])
+        {
+            return None;
+        }
+
+        let (mut oid, mut size) = (None, None);
+
+        for line in lines {
+            let mut pair = line.splitn(2, ' ');
+            match (pair.next(), pair.next()) {
+                (Some("oid"), Some(value)) if value.starts_with("sha256:") => {
+                    oid = Some(&value["sha256:".len()..])
+                }
+                (Some("size"), Some(value)) => size = value.parse::<u64>().ok(),
+                _ => (),
+            }
+        }
+
+        let oid = oid.filter(|oid| {
+            oid.len() == 64
+                && oid
+                    .bytes()
+                    .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+        })?;
+
+        Some(Pointer {
+            oid: oid.into(),
+            size: size?,
+        })
+    }
+}
+
+/// An object which is not in the LFS store of a mirror.
+#[derive(Debug, Clone)]
+pub struct Missing {
+    pub pointer: Pointer,
+    /// The LFS store of the mirror.
+    pub store: PathBuf,
+    /// The LFS server that the object is downloaded from.
+    pub endpoint: Option<Box<str>>,
+    /// The path of the object's pointer in the archive.
+    pub path: PathBuf,
+}
+
+/// The path of an object in an LFS store.
+pub fn object_path(store: &Path, oid: &str) -> PathBuf {
+    store
+        .join("objects")
+        .join(&oid[..2])
+        .join(&oid[2..4])
+        .join(oid)
+}
+
+/// The `url` of the `[lfs]` section of a `.lfsconfig`, which is in the format of git's config.
+pub fn lfsconfig_url(content: &str) -> Option<&str> {
+    let mut in_lfs = false;
+    let mut url = None;
+
+    for line in content.lines() {
+        let line = line.trim();
+
+        if line.starts_with('[') {
+            let section = line.trim_start_matches('[').trim_end_matches(']').trim();
+            in_lfs = section.eq_ignore_ascii_case("lfs");
+            continue;
+        }
+
+        let mut pair = line.splitn(2, '=');
+        if let (true, Some(key), Some(value)) = (in_lfs, pair.next(), pair.next()) {
+            if key.trim().eq_ignore_ascii_case("url") {
+                url = Some(value.trim().trim_matches('"'));
+            }
+        }
+    }
+
+    url
+}
+
+/// The LFS server of a remote, which `git lfs` derives from the URL of the remote.
+///
+/// Remotes which are accessed over SSH have their LFS server at the same path over HTTPS.
+pub fn endpoint(url: &str) -> Option<String> {
+    let url = url.trim_end_matches('/');
+
+    let url = if url.starts_with("https://") || url.starts_with("http://") {
+        url.to_owned()
+    } else if url.starts_with("ssh://") {
+        let mut parts = url["ssh://".len()..].splitn(2, '/');
+        let (authority, path) = (parts.next()?, parts.next()?);
+        let host = authority.rsplit('@').next()?.split(':').next()?;
+        ["https://", host, "/", path].concat()
+    } else if !url.contains("://") && url.contains(':') {
+        let mut parts = url.splitn(2, ':');
+        let (authority, path) = (parts.next()?, parts.next()?);
+        let host = authority.rsplit('@').next()?;
+        ["https://", host, "/", path.trim_start_matches('/')].concat()
+    } else {
+        return None;
+    };
+
+    let suffix = if url.ends_with(".git") {
+        "/info/lfs"
+    } else {
+        ".git/info/lfs"
+    };
+
+    Some([&url, suffix].concat())
+}
+
+#[derive(Serialize)]
+struct BatchRequest<'a> {
+    operation: &'a str,
+    transfers: &'a [&'a str],
+    objects: &'a [&'a Pointer],
+}
+
+#[derive(Deserialize)]
+struct BatchResponse {
+    objects: Vec<BatchObject>,
+}
+
+#[derive(Deserialize)]
+struct BatchObject {
+    oid: Box<str>,
+    #[serde(default)]
+    actions: Option<BatchActions>,
+    #[serde(default)]
+    error: Option<BatchError>,
+}
+
+#[derive(Deserialize)]
+struct BatchActions {
+    download: Option<BatchAction>,
+}
+
+#[derive(Deserialize)]
+struct BatchAction {
+    href: Box<str>,
+    #[serde(default)]
+    header: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct BatchError {
+    code: u16,
+    message: Box<str>,
+}
+
+/// Downloads missing objects into the LFS stores of their mirrors, with one batch request for
+/// each store.
+pub async fn download(client: &Client, missing: &[Missing]) -> io::Result<()> {
+    let mut batches = HashMap::<(&Path, &str), Vec<&Missing>>::new();
+
+    for object in missing {
+        let endpoint = object.endpoint.as_ref().ok_or_else(|| {
+            invalid_data(format!(
+                "LFS object {} of {} is not stored, and its repository has no LFS server",
+                object.pointer.oid,
+                object.path.display()
+            ))
+        })?;
+
+        let batch = batches
+            .entry((object.store.as_path(), &**endpoint))
+            .or_default();
+
+        // Files with the same content have the same object.
+        if !batch.iter().any(|other| other.pointer == object.pointer) {
+            batch.push(object);
+        }
+    }
+
+    for ((store, endpoint), objects) in batches {
+        download_batch(client, store, endpoint, &objects).await?;
+    }
+
+    Ok(())
+}
+
+async fn download_batch(
+    client: &Client,
+    store: &Path,
+    endpoint: &str,
+    objects: &[&Missing],
+) -> io::Result<()> {
+    info!(
+        "downloading {} LFS objects from {}",
+        objects.len(),
+        endpoint
+    );
+
+    let pointers = objects
+        .iter()
+        .map(|object| &object.pointer)
+        .collect::<Vec<_>>();
+
+    let mut request = client
+        .post(&*[endpoint, "/objects/batch"].concat())
+        .header("accept", MEDIA_TYPE)
+        .header("content-type", MEDIA_TYPE)
+        .json(&BatchRequest {
+            operation: "download",
+            transfers: &["basic"],
+            objects: &pointers,
+        });
+
+    if let Some((username, password)) = credentials(endpoint).await {
+        request = request.basic_auth(username, Some(password));
+    }
+
+    let response = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|why| batch_error(endpoint, why))?
+        .json::<BatchResponse>()
+        .await
+        .map_err(|why| batch_error(endpoint, why))?;
+
+    for object in objects {
+        let pointer = &object.pointer;
+
+        let action = response
+            .objects
+            .iter()
+            .find(|batch| batch.oid == pointer.oid)
+            .ok_or_else(|| "the server did not return it".into())
+            .and_then(|batch| match (&batch.error, &batch.actions) {
+                (Some(error), _) => Err(format!("{} ({})", error.message, error.code)),
+                (
+                    None,
+                    Some(BatchActions {
+                        download: Some(action),
+                    }),
+                ) => Ok(action),
+                (None, _) => Err("the server did not return a download for it".into()),
+            })
+            .map_err(|why: String| {
+                invalid_data(format!(
+                    "LFS object {} of {} could not be downloaded from {}: {}",
+                    pointer.oid,
+                    object.path.display(),
+                    endpoint,
+                    why
+                ))
+            })?;
+
+        download_object(client, store, pointer, action).await?;
+    }
+
+    Ok(())
+}
+
+/// Downloads an object beside its path in the store, and renames it once it is verified.
+async fn download_object(
+    client: &Client,
+    store: &Path,
+    pointer: &Pointer,
+    action: &BatchAction,
+) -> io::Result<()> {
+    let path = object_path(store, &pointer.oid);
+    let download_error = |why: reqwest::Error| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("failed to download LFS object {}: {}", pointer.oid, why),
+        )
+    };
+
+    let mut request = client.get(&*action.href);
+    for (name, value) in &action.header {
+        request = request.header(name.as_str(), value.as_str());
+    }
+
+    let mut response = request
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(download_error)?;
+
+    // Files are written on the blocking thread pool, so that they do not stall the executor.
+    let partial = super::partial_path(&path);
+    let mut file = {
+        let partial = partial.clone();
+        super::blocking(move || {
+            fs::create_dir_all(partial.parent().expect("object path without parent"))?;
+            File::create(&partial)
+        })
+        .await?
+    };
+
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+
+    let failure = loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => {
+                let oid = format!("{:x}", hasher.result());
+                if *oid != *pointer.oid || size != pointer.size {
+                    break Some(invalid_data(format!(
+                        "downloaded LFS object {} does not match its pointer: {} bytes with oid {}",
+                        pointer.oid, size, oid
+                    )));
+                }
+
+                break None;
+            }
+            Err(why) => break Some(download_error(why)),
+        };
+
+        hasher.input(&chunk);
+        size += chunk.len() as u64;
+
+        // The closure owns the file while writing, so hands it back with the result.
+        let (returned, written) = super::blocking(move || {
+            let written = file.write_all(&chunk);
+            Ok((file, written))
+        })
+        .await?;
+
+        file = returned;
+        if let Err(why) = written {
+            break Some(why);
+        }
+    };
+
+    super::blocking(move || {
+        if let Some(why) = failure {
+            let _ = fs::remove_file(&partial);
+            return Err(why);
+        }
+
+        file.sync_all()?;
+        fs::rename(&partial, &path)
+    })
+    .await
+}
+
+/// Asks git's credential helpers for the credentials of an LFS server, on the blocking thread
+/// pool, as helpers are external commands.
+async fn credentials(url: &str) -> Option<(String, String)> {
+    let url = url.to_owned();
+
+    let helper = move || {
+        let credentials = git2::Config::open_default()
+            .ok()
+            .and_then(|config| git2::CredentialHelper::new(&url).config(&config).execute());
+
+        Ok(credentials)
+    };
+
+    super::blocking(helper).await.unwrap_or(None)
+}
+
+fn batch_error(endpoint: &str, why: reqwest::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("LFS batch request to {} failed: {}", endpoint, why),
+    )
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OID: &str = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+
+    fn pointer(version: &str, oid: &str, size: &str) -> String {
+        [
+            "version ",
+            version,
+            "\noid sha256:",
+            oid,
+            "\nsize ",
+            size,
+            "\n",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn parses_pointers() {
+        let expected = Some(Pointer {
+            oid: OID.into(),
+            size: 12345,
+        });
+
+        for version in POINTER_VERSIONS {
+            let content = pointer(version, OID, "12345");
+            assert_eq!(Pointer::parse(content.as_bytes()), expected);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_pointers() {
+        let version = POINTER_VERSIONS[0];
+
+        let invalid = [
+            pointer("https://example.com/spec/v1", OID, "12345"),
+            pointer(version, &OID[1..], "12345"),
+            pointer(version, &OID.to_ascii_uppercase(), "12345"),
+            pointer(version, OID, "-1"),
+            ["version ", version, "\noid sha256:", OID, "\n"].concat(),
+            ["version ", version, "\noid md5:", OID, "\nsize 1\n"].concat(),
+        ];
+
+        for content in &invalid {
+            assert_eq!(Pointer::parse(content.as_bytes()), None, "{}", content);
+        }
+    }
+
+    #[test]
+    fn blobs_are_not_pointers() {
+        assert_eq!(Pointer::parse(b""), None);
+        assert_eq!(Pointer::parse(b"hello world\n"), None);
+        assert_eq!(Pointer::parse(&[0xff, 0xfe, 0x00, 0x01]), None);
+
+        // Files larger than any pointer are never parsed, even if they begin like one.
+        let mut large = pointer(POINTER_VERSIONS[0], OID, "12345");
+        large.push_str(&" ".repeat(POINTER_MAX_SIZE));
+        assert_eq!(Pointer::parse(large.as_bytes()), None);
+    }
+
+    #[test]
+    fn lfsconfig_urls() {
+        let config = "[lfs]\n\turl = \"https://lfs.example.com/hello\"\n";
+        assert_eq!(lfsconfig_url(config), Some("https://lfs.example.com/hello"));
+
+        let config = "[core]\n\turl = ignored\n[LFS]\n\tURL=https://lfs.example.com\n";
+        assert_eq!(lfsconfig_url(config), Some("https://lfs.example.com"));
+
+        let config = "[remote \"origin\"]\n\turl = https://example.com/hello.git\n";
+        assert_eq!(lfsconfig_url(config), None);
+        assert_eq!(lfsconfig_url(""), None);
+    }
+
+    #[test]
+    fn endpoints() {
+        let lfs = "https://github.com/pop-os/hello.git/info/lfs";
+
+        let remotes = [
+            "https://github.com/pop-os/hello.git",
+            "https://github.com/pop-os/hello",
+            "https://github.com/pop-os/hello/",
+            "git@github.com:pop-os/hello.git",
+            "github.com:pop-os/hello",
+            "ssh://git@github.com/pop-os/hello.git",
+            "ssh://git@github.com:22/pop-os/hello.git",
+        ];
+
+        for remote in &remotes {
+            assert_eq!(
+                endpoint(remote).as_ref().map(String::as_str),
+                Some(lfs),
+                "{}",
+                remote
+            );
+        }
+
+        assert_eq!(
+            endpoint("http://git.example.com/hello")
+                .as_ref()
+                .map(String::as_str),
+            Some("http://git.example.com/hello.git/info/lfs")
+        );
+
+        assert_eq!(endpoint("/srv/git/hello.git"), None);
+        assert_eq!(endpoint("file:///srv/git/hello.git"), None);
+        assert_eq!(endpoint("ssh://github.com"), None);
+    }
+
+    #[test]
+    fn object_paths() {
+        assert_eq!(
+            object_path(Path::new("lfs"), OID),
+            Path::new("lfs/objects/4d/7a").join(OID)
+        );
+    }
+}
@@ -0,0 +1,947 @@
+//! Git operations on the bare mirrors of repositories, performed in-process with libgit2.
+//!
+//! Archives are written from the tree of a commit, like `git archive`, which honors the
+//! `export-ignore` and `export-subst` attributes of `.gitattributes`. Credentials of remotes
+//! come from git's credential helpers. Work on repositories runs on the blocking thread pool.
+//!
+//! Submodules are archived at the commits recorded by their superproject. Each submodule is
+//! mirrored within the mirror of its superproject, at `modules/<name>`, as git itself does.
+//! Files which `.gitattributes` stores in Git LFS are archived with the content of their LFS
+//! objects, rather than their pointers.
+
+mod attributes;
+mod lfs;
+
+use self::attributes::{Attribute, Attributes};
+use chrono::{DateTime, FixedOffset, TimeZone};
+use git2::{
+    build::RepoBuilder, Commit, Cred, ErrorCode, FetchOptions, FetchPrune, ObjectType, Oid,
+    RemoteCallbacks, Repository, Time, Tree,
+};
+use reqwest::Client;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tar::{Builder, EntryType, Header};
+
+/// Distinguishes the partial files of a path which is written more than once at a time.
+static PARTIALS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct GitTar {
+    pub id: Box<str>,
+    pub datetime: Box<str>,
+    pub archive: Box<Path>,
+    pub timestamp: Box<str>,
+}
+
+impl GitTar {
+    /// Archives the tree of a commit if it has not been archived yet, and looks up its
+    /// timestamp and date. LFS objects are downloaded with the client.
+    pub async fn new<'a>(
+        client: &Client,
+        cwd: &Path,
+        archive_path: &Path,
+        sha: &'a str,
+    ) -> io::Result<Self> {
+        if archive_path.exists() {
+            info!(
+                "{} commit {}: git already built",
+                cwd.file_name().unwrap().to_str().unwrap(),
+                sha
+            );
+        } else {
+            archive(client, cwd, sha, archive_path).await?;
+        }
+
+        let (cwd, id) = (cwd.to_owned(), sha.to_owned());
+        let time = blocking(move || Ok(find_commit(&open(&cwd)?, &id)?.time())).await?;
+
+        Ok(Self {
+            id: sha.into(),
+            timestamp: time.seconds().to_string().into(),
+            datetime: rfc2822(time).into(),
+            archive: archive_path.into(),
+        })
+    }
+}
+
+pub async fn archive_id(client: &Client, cwd: &Path, id: &str, archive: &str) -> io::Result<()> {
+    self::archive(client, cwd, id, Path::new(archive)).await
+}
+
+/// Fetches every ref of a mirror's remote, removing refs which no longer exist.
+pub async fn fetch(cwd: &Path, remote: &str) -> io::Result<()> {
+    let (cwd, remote) = (cwd.to_owned(), remote.to_owned());
+    blocking(move || fetch_remote(&open(&cwd)?, &remote)).await
+}
+
+/// Creates a bare mirror of a remote, which holds its branches, tags, and pull requests.
+pub async fn mirror(url: &str, cwd: &Path) -> io::Result<()> {
+    let (url, cwd) = (url.to_owned(), cwd.to_owned());
+    blocking(move || clone_mirror(&url, &cwd)).await
+}
+
+pub async fn datetime_id(cwd: &Path, id: &str) -> io::Result<String> {
+    let (cwd, id) = (cwd.to_owned(), id.to_owned());
+    blocking(move || Ok(rfc2822(find_commit(&open(&cwd)?, &id)?.time()))).await
+}
+
+/// Checks if a directory exists in the tree of a commit, without checking it out.
+pub async fn has_dir(cwd: &Path, id: &str, path: &str) -> io::Result<bool> {
+    let (cwd, id, path) = (cwd.to_owned(), id.to_owned(), path.to_owned());
+
+    blocking(move || {
+        let repo = open(&cwd)?;
+        let tree = find_commit(&repo, &id)?.tree().map_err(other)?;
+
+        match tree.get_path(Path::new(&path)) {
+            Ok(entry) => Ok(entry.kind() == Some(ObjectType::Tree)),
+            Err(ref why) if why.code() == ErrorCode::NotFound => Ok(false),
+            Err(why) => Err(other(why)),
+        }
+    })
+    .await
+}
+
+/// The commits that every ref points to, with annotated tags peeled to their commits.
+pub async fn ref_ids(cwd: &Path) -> io::Result<HashSet<Box<str>>> {
+    let cwd = cwd.to_owned();
+
+    blocking(move || {
+        let repo = open(&cwd)?;
+        let mut ids = HashSet::new();
+
+        for reference in repo.references().map_err(other)? {
+            let reference = reference.map_err(other)?;
+
+            if let Some(id) = reference.target() {
+                ids.insert(id.to_string().into());
+            }
+
+            if let Ok(commit) = reference.peel_to_commit() {
+                ids.insert(commit.id().to_string().into());
+            }
+        }
+
+        Ok(ids)
+    })
+    .await
+}
+
+/// Fetches the remote, and maps the commit of each of its branches to the branches at it.
+///
+/// The mirror is pruned when it is fetched, so its branches are those of the remote.
+pub async fn ids_and_branches(
+    map: &mut HashMap<Box<str>, Vec<Box<str>>>,
+    cwd: &Path,
+) -> io::Result<()> {
+    fetch(cwd, "origin").await?;
+    map.clear();
+
+    for (branch, id) in local_refs(cwd, "refs/heads/").await? {
+        map.entry(id).or_insert_with(Vec::new).push(branch);
+    }
+
+    Ok(())
+}
+
+/// Lists the tags of the mirror, and the commits that they point to.
+pub async fn remote_tags(cwd: &Path) -> io::Result<Vec<(Box<str>, Box<str>)>> {
+    local_refs(cwd, "refs/tags/").await
+}
+
+pub async fn timestamp_id(cwd: &Path, id: &str) -> io::Result<String> {
+    let (cwd, id) = (cwd.to_owned(), id.to_owned());
+    blocking(move || Ok(find_commit(&open(&cwd)?, &id)?.time().seconds().to_string())).await
+}
+
+/// Runs libgit2 work on the blocking thread pool, so that it does not stall the executor.
+async fn blocking<T, F>(func: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    tokio_executor::blocking::run(func).await
+}
+
+async fn local_refs(cwd: &Path, prefix: &'static str) -> io::Result<Vec<(Box<str>, Box<str>)>> {
+    let cwd = cwd.to_owned();
+    blocking(move || refs(&open(&cwd)?, prefix)).await
+}
+
+fn open(cwd: &Path) -> io::Result<Repository> {
+    Repository::open(cwd).map_err(other)
+}
+
+fn find_commit<'r>(repo: &'r Repository, id: &str) -> io::Result<Commit<'r>> {
+    repo.revparse_single(id)
+        .and_then(|object| object.peel_to_commit())
+        .map_err(other)
+}
+
+fn other(why: git2::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, why)
+}
+
+/// Formats the time of a commit like `%cD` of `git log`.
+fn rfc2822(time: Time) -> String {
+    offset_time(time).to_rfc2822()
+}
+
+fn offset_time(time: Time) -> DateTime<FixedOffset> {
+    FixedOffset::east(time.offset_minutes() * 60).timestamp(time.seconds(), 0)
+}
+
+/// The refs with a prefix, named without the prefix, and the commits that they point to.
+fn refs(repo: &Repository, prefix: &str) -> io::Result<Vec<(Box<str>, Box<str>)>> {
+    let mut refs = Vec::new();
+
+    for reference in repo.references().map_err(other)? {
+        let reference = reference.map_err(other)?;
+
+        let name = match reference.name() {
+            Some(name) if name.starts_with(prefix) => &name[prefix.len()..],
+            _ => continue,
+        };
+
+        // Tags may point to trees and blobs, which are not built.
+        if let Ok(commit) = reference.peel_to_commit() {
+            refs.push((name.into(), commit.id().to_string().into()));
+        }
+    }
+
+    Ok(refs)
+}
+
+fn fetch_remote(repo: &Repository, remote: &str) -> io::Result<()> {
+    let mut remote = repo.find_remote(remote).map_err(other)?;
+
+    let mut options = fetch_options();
+    options.prune(FetchPrune::On);
+
+    // The refspecs of the remote are used when none are given.
+    remote
+        .fetch(&[] as &[&str], Some(&mut options), None)
+        .map_err(other)
+}
+
+fn clone_mirror(url: &str, cwd: &Path) -> io::Result<()> {
+    RepoBuilder::new()
+        .bare(true)
+        .fetch_options(fetch_options())
+        .remote_create(|repo, name, url| repo.remote_with_fetch(name, url, "+refs/*:refs/*"))
+        .clone(url, cwd)
+        .map(|_| ())
+        .map_err(other)
+}
+
+fn fetch_options<'a>() -> FetchOptions<'a> {
+    let mut attempted = false;
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, _| {
+        // Rejected credentials are asked for again, which a helper would only repeat.
+        if attempted {
+            return Err(git2::Error::from_str("credentials were rejected"));
+        }
+
+        attempted = true;
+        let config = git2::Config::open_default()?;
+        Cred::credential_helper(&config, url, username)
+    });
+
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks);
+    options
+}
+
+/// A submodule of a superproject, as declared by its `.gitmodules`.
+struct Submodule {
+    name: Box<str>,
+    url: Box<str>,
+}
+
+/// A repository whose commit is being archived.
+struct Source<'r> {
+    repo: &'r Repository,
+    /// The mirror of the repository, which holds the mirrors of its submodules.
+    dir: &'r Path,
+    /// The remote of the repository, which relative submodule URLs are relative to.
+    url: Option<Box<str>>,
+    /// The submodules of the commit, by their path in its tree.
+    submodules: HashMap<PathBuf, Submodule>,
+    /// The LFS server of the commit.
+    lfs: Option<Box<str>>,
+    /// The commit being archived, which `export-subst` placeholders are expanded with.
+    commit: &'r Commit<'r>,
+    /// The rules of the `.gitattributes` of the directories being archived.
+    attributes: Attributes,
+}
+
+/// A tar archive which is being written, and the LFS objects that are missing from it.
+struct Archive<W: Write> {
+    builder: Builder<W>,
+    mtime: u64,
+    missing: Vec<lfs::Missing>,
+}
+
+/// Archives a commit, downloading the LFS objects that are missing from the stores of the
+/// mirrors, and archiving it again with them.
+async fn archive(client: &Client, cwd: &Path, id: &str, path: &Path) -> io::Result<()> {
+    let archive = || {
+        let (cwd, id, path) = (cwd.to_owned(), id.to_owned(), path.to_owned());
+        blocking(move || archive_commit(&cwd, &id, &path))
+    };
+
+    let missing = archive().await?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    lfs::download(client, &missing).await?;
+
+    match archive().await?.first() {
+        None => Ok(()),
+        Some(object) => Err(invalid_data(format!(
+            "LFS object {} of {} is missing",
+            object.pointer.oid,
+            object.path.display()
+        ))),
+    }
+}
+
+/// Writes the tree of a commit to a tar archive, with the commit's time as every mtime.
+///
+/// The archive is written beside its path, and renamed once complete, so that an interrupted
+/// archive is never mistaken for a complete one. Archives which lack LFS objects are removed,
+/// and the missing objects are returned.
+fn archive_commit(cwd: &Path, id: &str, path: &Path) -> io::Result<Vec<lfs::Missing>> {
+    let repo = open(cwd)?;
+    let commit = find_commit(&repo, id)?;
+
+    let url = repo
+        .find_remote("origin")
+        .ok()
+        .and_then(|remote| remote.url().map(Box::from));
+
+    let partial = partial_path(path);
+
+    let mut archive = Archive {
+        builder: Builder::new(File::create(&partial)?),
+        mtime: commit.time().seconds().max(0) as u64,
+        missing: Vec::new(),
+    };
+
+    append_commit(&repo, cwd, url, &commit, &mut archive, Path::new(""))?;
+
+    let Archive {
+        builder, missing, ..
+    } = archive;
+
+    if !missing.is_empty() {
+        drop(builder);
+        fs::remove_file(&partial)?;
+        return Ok(missing);
+    }
+
+    builder.into_inner()?.sync_all()?;
+    fs::rename(&partial, path)?;
+
+    Ok(Vec::new())
+}
+
+fn append_commit<'r, W: Write>(
+    repo: &'r Repository,
+    dir: &Path,
+    url: Option<Box<str>>,
+    commit: &'r Commit<'r>,
+    archive: &mut Archive<W>,
+    prefix: &Path,
+) -> io::Result<()> {
+    let tree = commit.tree().map_err(other)?;
+    let submodules = submodules(repo, &tree)?;
+    let lfs = lfs_endpoint(repo, &tree, url.as_ref().map(|url| &**url));
+
+    let mut source = Source {
+        repo,
+        dir,
+        url,
+        submodules,
+        lfs,
+        commit,
+        attributes: Attributes::default(),
+    };
+
+    append_tree(&mut source, archive, &tree, prefix, Path::new(""))
+}
+
+/// Appends the entries of a tree, which is at `path` in the tree of its commit.
+fn append_tree<W: Write>(
+    source: &mut Source,
+    archive: &mut Archive<W>,
+    tree: &Tree,
+    prefix: &Path,
+    path: &Path,
+) -> io::Result<()> {
+    let repo = source.repo;
+    let mtime = archive.mtime;
+
+    let inherited = source.attributes.count();
+    if let Some(entry) = tree.get_name(".gitattributes") {
+        let blob = repo.find_blob(entry.id()).map_err(other)?;
+        let content = String::from_utf8_lossy(blob.content());
+        source.attributes.push(path, &content);
+    }
+
+    for entry in tree.iter() {
+        let name = OsStr::from_bytes(entry.name_bytes());
+        let (archived, path) = (prefix.join(name), path.join(name));
+
+        if source.attributes.is_set(&path, Attribute::ExportIgnore) {
+            continue;
+        }
+
+        match entry.kind() {
+            Some(ObjectType::Tree) => {
+                let subtree = repo.find_tree(entry.id()).map_err(other)?;
+                let mut header = tar_header(EntryType::Directory, 0o775, mtime, 0);
+                archive
+                    .builder
+                    .append_data(&mut header, &archived, io::empty())?;
+                append_tree(source, archive, &subtree, &archived, &path)?;
+            }
+            Some(ObjectType::Blob) => {
+                let blob = repo.find_blob(entry.id()).map_err(other)?;
+                let content = blob.content();
+
+                match entry.filemode() {
+                    // The content of a symlink is its target.
+                    0o120000 => {
+                        let mut header = tar_header(EntryType::Symlink, 0o777, mtime, 0);
+                        header.set_link_name(OsStr::from_bytes(content))?;
+                        archive
+                            .builder
+                            .append_data(&mut header, &archived, io::empty())?;
+                    }
+                    filemode => {
+                        let mode = if filemode == 0o100755 { 0o775 } else { 0o664 };
+
+                        // Pointers are only replaced where `.gitattributes` stores files in LFS.
+                        let pointer = if source.attributes.is_set(&path, Attribute::Lfs) {
+                            lfs::Pointer::parse(content)
+                        } else {
+                            None
+                        };
+
+                        match pointer {
+                            Some(pointer) => {
+                                append_lfs(source, archive, pointer, &archived, mode)?;
+                            }
+                            None if source.attributes.is_set(&path, Attribute::ExportSubst) => {
+                                let content = substitute(repo, source.commit, content);
+                                let size = content.len() as u64;
+                                let mut header = tar_header(EntryType::Regular, mode, mtime, size);
+                                archive
+                                    .builder
+                                    .append_data(&mut header, &archived, &*content)?;
+                            }
+                            None => {
+                                let size = content.len() as u64;
+                                let mut header = tar_header(EntryType::Regular, mode, mtime, size);
+                                archive
+                                    .builder
+                                    .append_data(&mut header, &archived, content)?;
+                            }
+                        }
+                    }
+                }
+            }
+            // Submodules are the commit of another repository, whose tree is archived in place.
+            Some(ObjectType::Commit) => {
+                let mut header = tar_header(EntryType::Directory, 0o775, mtime, 0);
+                archive
+                    .builder
+                    .append_data(&mut header, &archived, io::empty())?;
+                append_submodule(source, archive, &path, entry.id(), &archived)?;
+            }
+            _ => (),
+        }
+    }
+
+    source.attributes.truncate(inherited);
+
+    Ok(())
+}
+
+/// Expands the `$Format:<format>$` placeholders of a file, like `export-subst` does.
+///
+/// Placeholders whose format spans lines are left as they are.
+fn substitute(repo: &Repository, commit: &Commit, content: &[u8]) -> Vec<u8> {
+    const START: &[u8] = b"$Format:";
+
+    let mut output = Vec::with_capacity(content.len());
+    let mut rest = content;
+
+    while let Some(start) = find(rest, START) {
+        let format = &rest[start + START.len()..];
+        let end = match format.iter().position(|&b| b == b'$' || b == b'\n') {
+            Some(end) if format[end] == b'$' => end,
+            _ => {
+                output.extend_from_slice(&rest[..start + START.len()]);
+                rest = format;
+                continue;
+            }
+        };
+
+        output.extend_from_slice(&rest[..start]);
+        let format = String::from_utf8_lossy(&format[..end]);
+        output.extend_from_slice(format_commit(repo, commit, &format).as_bytes());
+        rest = &rest[start + START.len() + end + 1..];
+    }
+
+    output.extend_from_slice(rest);
+    output
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Formats a commit with the placeholders of `git log --pretty=format:`. Placeholders which
+/// are not supported are left as they are.
+fn format_commit(repo: &Repository, commit: &Commit, format: &str) -> String {
+    let (author, committer) = (commit.author(), commit.committer());
+    let short = |id: Oid| {
+        repo.find_object(id, None)
+            .and_then(|object| object.short_id())
+            .ok()
+            .and_then(|id| id.as_str().map(String::from))
+            .unwrap_or_else(|| id.to_string()[..7].to_owned())
+    };
+
+    let mut output = String::with_capacity(format.len());
+    let mut chars = format.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            output.push(c);
+            continue;
+        }
+
+        let rest = chars.as_str();
+        let placeholder = if rest.starts_with('a') || rest.starts_with('c') {
+            rest.get(..2)
+        } else {
+            rest.get(..rest.chars().next().map_or(0, char::len_utf8))
+        };
+
+        let (signature, field) = match placeholder {
+            Some(placeholder) if placeholder.len() == 2 => {
+                let signature = if placeholder.starts_with('a') {
+                    &author
+                } else {
+                    &committer
+                };
+                (Some(signature), &placeholder[1..])
+            }
+            Some(placeholder) => (None, placeholder),
+            None => {
+                output.push('%');
+                continue;
+            }
+        };
+
+        let expanded = match (signature, field) {
+            (None, "H") => Some(commit.id().to_string()),
+            (None, "h") => Some(short(commit.id())),
+            (None, "T") => Some(commit.tree_id().to_string()),
+            (None, "t") => Some(short(commit.tree_id())),
+            (None, "P") => Some(
+                commit
+                    .parent_ids()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            (None, "p") => Some(commit.parent_ids().map(short).collect::<Vec<_>>().join(" ")),
+            (None, "s") => Some(commit.summary().unwrap_or("").to_owned()),
+            (None, "b") => Some(body(commit.message().unwrap_or(""))),
+            (None, "B") => Some(commit.message().unwrap_or("").to_owned()),
+            (None, "n") => Some("\n".to_owned()),
+            (None, "%") => Some("%".to_owned()),
+            (Some(signature), "n") => Some(signature.name().unwrap_or("").to_owned()),
+            (Some(signature), "e") => Some(signature.email().unwrap_or("").to_owned()),
+            (Some(signature), "d") => Some(
+                offset_time(signature.when())
+                    .format("%a %b %-d %H:%M:%S %Y %z")
+                    .to_string(),
+            ),
+            (Some(signature), "D") => Some(rfc2822(signature.when())),
+            (Some(signature), "t") => Some(signature.when().seconds().to_string()),
+            (Some(signature), "I") => Some(offset_time(signature.when()).to_rfc3339()),
+            (Some(signature), "i") => Some(
+                offset_time(signature.when())
+                    .format("%Y-%m-%d %H:%M:%S %z")
+                    .to_string(),
+            ),
+            _ => None,
+        };
+
+        match expanded {
+            Some(expanded) => {
+                output.push_str(&expanded);
+                let consumed = placeholder.map_or(0, str::len);
+                chars = rest[consumed..].chars();
+            }
+            None => output.push('%'),
+        }
+    }
+
+    output
+}
+
+/// The body of a commit message, which follows the blank line after its subject.
+fn body(message: &str) -> String {
+    let mut paragraphs = message.splitn(2, "\n\n");
+    paragraphs.next();
+    paragraphs.next().unwrap_or("").to_owned()
+}
+
+/// Appends the LFS object of a pointer from the store of the mirror, or records it as missing.
+fn append_lfs<W: Write>(
+    source: &Source,
+    archive: &mut Archive<W>,
+    pointer: lfs::Pointer,
+    path: &Path,
+    mode: u32,
+) -> io::Result<()> {
+    let store = source.dir.join("lfs");
+
+    let file = match File::open(lfs::object_path(&store, &pointer.oid)) {
+        Ok(file) => file,
+        Err(ref why) if why.kind() == io::ErrorKind::NotFound => {
+            archive.missing.push(lfs::Missing {
+                pointer,
+                store,
+                endpoint: source.lfs.clone(),
+                path: path.into(),
+            });
+
+            return Ok(());
+        }
+        Err(why) => return Err(why),
+    };
+
+    if file.metadata()?.len() != pointer.size {
+        return Err(invalid_data(format!(
+            "LFS object {} of {} does not have the size of its pointer",
+            pointer.oid,
+            path.display()
+        )));
+    }
+
+    let mut header = tar_header(EntryType::Regular, mode, archive.mtime, pointer.size);
+    archive.builder.append_data(&mut header, path, file)
+}
+
+/// The LFS server of a commit, which is the `lfs.url` of the mirror's config or the commit's
+/// `.lfsconfig`, or else the LFS server of the repository's remote.
+fn lfs_endpoint(repo: &Repository, tree: &Tree, url: Option<&str>) -> Option<Box<str>> {
+    let config = repo
+        .config()
+        .ok()
+        .and_then(|config| config.get_string("lfs.url").ok());
+
+    let lfsconfig = || {
+        let entry = tree.get_name(".lfsconfig")?;
+        let blob = repo.find_blob(entry.id()).ok()?;
+        lfs::lfsconfig_url(&String::from_utf8_lossy(blob.content())).map(String::from)
+    };
+
+    config
+        .or_else(lfsconfig)
+        .or_else(|| lfs::endpoint(url?))
+        .map(Box::from)
+}
+
+/// Appends the tree of a submodule's commit, and of its own submodules.
+fn append_submodule<W: Write>(
+    source: &Source,
+    archive: &mut Archive<W>,
+    path: &Path,
+    id: Oid,
+    prefix: &Path,
+) -> io::Result<()> {
+    // Like `git archive`, gitlinks which are not declared are left as empty directories.
+    let submodule = match source.submodules.get(path) {
+        Some(submodule) => submodule,
+        None => {
+            warn!(
+                "submodule at {} is not declared in .gitmodules, so it is archived empty",
+                path.display()
+            );
+            return Ok(());
+        }
+    };
+
+    let url =
+        submodule_url(source.url.as_ref().map(|url| &**url), &submodule.url).ok_or_else(|| {
+            invalid_data(format!(
+                "submodule {} has a relative URL, but its superproject has no remote",
+                submodule.name
+            ))
+        })?;
+
+    // Names are joined to the mirror's path, so must not escape it.
+    let name = Path::new(&*submodule.name);
+    if name.is_absolute() || name.components().any(|c| c == Component::ParentDir) {
+        return Err(invalid_data(format!(
+            "submodule name {:?} is not a valid path",
+            submodule.name
+        )));
+    }
+
+    let dir = source.dir.join("modules").join(name);
+
+    if !dir.exists() {
+        info!("submodule {}: mirroring {}", submodule.name, url);
+        clone_mirror(&url, &dir)?;
+    }
+
+    let repo = open(&dir)?;
+
+    if repo.find_commit(id).is_err() {
+        info!("submodule {}: fetching {}", submodule.name, url);
+        repo.remote_set_url("origin", &url).map_err(other)?;
+        fetch_remote(&repo, "origin")?;
+    }
+
+    let commit = repo.find_commit(id).map_err(|_| {
+        invalid_data(format!(
+            "submodule {}: commit {} was not found in {}",
+            submodule.name, id, url
+        ))
+    })?;
+
+    append_commit(&repo, &dir, Some(url.into()), &commit, archive, prefix)
+}
+
+/// Reads the submodules declared by the `.gitmodules` of a tree.
+fn submodules(repo: &Repository, tree: &Tree) -> io::Result<HashMap<PathBuf, Submodule>> {
+    let entry = match tree.get_path(Path::new(".gitmodules")) {
+        Ok(entry) => entry,
+        Err(ref why) if why.code() == ErrorCode::NotFound => return Ok(HashMap::new()),
+        Err(why) => return Err(other(why)),
+    };
+
+    let blob = repo.find_blob(entry.id()).map_err(other)?;
+    Ok(parse_gitmodules(&String::from_utf8_lossy(blob.content())))
+}
+
+/// Parses the `path` and `url` of each `[submodule "<name>"]` section of a `.gitmodules`.
+fn parse_gitmodules(content: &str) -> HashMap<PathBuf, Submodule> {
+    let mut sections: Vec<(Box<str>, Option<&str>, Option<&str>)> = Vec::new();
+    let mut in_submodule = false;
+
+    for line in content.lines() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            let header = line.trim_start_matches('[').trim_end_matches(']').trim();
+            in_submodule = header.starts_with("submodule");
+            if in_submodule {
+                let name = header["submodule".len()..].trim().trim_matches('"');
+                sections.push((name.into(), None, None));
+            }
+
+            continue;
+        }
+
+        let mut pair = line.splitn(2, '=');
+        let (key, value) = match (pair.next(), pair.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim().trim_matches('"')),
+            _ => continue,
+        };
+
+        if !in_submodule {
+            continue;
+        }
+
+        if let Some((_, path, url)) = sections.last_mut() {
+            match &*key.to_ascii_lowercase() {
+                "path" => *path = Some(value),
+                "url" => *url = Some(value),
+                _ => (),
+            }
+        }
+    }
+
+    sections
+        .into_iter()
+        .filter_map(|(name, path, url)| {
+            let submodule = Submodule {
+                name,
+                url: url?.into(),
+            };
+            Some((PathBuf::from(path?.trim_end_matches('/')), submodule))
+        })
+        .collect()
+}
+
+/// Resolves the URL of a submodule, which may be relative to the URL of its superproject.
+fn submodule_url(base: Option<&str>, url: &str) -> Option<String> {
+    if !url.starts_with("./") && !url.starts_with("../") {
+        return Some(url.to_owned());
+    }
+
+    let mut base = base?.trim_end_matches('/');
+    let mut url = url;
+    // The host of an scp-style URL is followed by a `:`, which is kept.
+    let mut separator = "/";
+
+    loop {
+        if url.starts_with("./") {
+            url = &url[2..];
+        } else if url.starts_with("../") {
+            url = &url[3..];
+            let pos = base.rfind(|c| c == '/' || c == ':')?;
+            if &base[pos..=pos] == ":" {
+                separator = ":";
+            }
+
+            base = &base[..pos];
+        } else {
+            break;
+        }
+    }
+
+    Some([base, separator, url].concat())
+}
+
+/// A path beside `path` which is unique to this write of it, so that concurrent writes of the
+/// same path do not clobber each other before being renamed to it.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().expect("path without file name").to_owned();
+    name.push(
+        [
+            ".",
+            &process::id().to_string(),
+            "-",
+            &PARTIALS.fetch_add(1, Ordering::SeqCst).to_string(),
+            ".partial",
+        ]
+        .concat(),
+    );
+
+    path.with_file_name(name)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn tar_header(kind: EntryType, mode: u32, mtime: u64, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
+    header.set_size(size);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gitmodules() {
+        let gitmodules = r#"
+            # Submodules of the superproject
+            [core]
+                path = not-a-submodule
+            [submodule "gtk"]
+                path = themes/gtk/
+                url = https://github.com/pop-os/gtk-theme
+            [submodule "icons"]
+                url = "../icons"
+                path = "icons"
+                branch = master
+            [submodule "missing-url"]
+                path = missing
+        "#;
+
+        let submodules = parse_gitmodules(gitmodules);
+        let mut paths = submodules.keys().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, vec![Path::new("icons"), Path::new("themes/gtk")]);
+
+        let gtk = &submodules[Path::new("themes/gtk")];
+        assert_eq!(&*gtk.name, "gtk");
+        assert_eq!(&*gtk.url, "https://github.com/pop-os/gtk-theme");
+
+        let icons = &submodules[Path::new("icons")];
+        assert_eq!(&*icons.name, "icons");
+        assert_eq!(&*icons.url, "../icons");
+    }
+
+    #[test]
+    fn resolves_submodule_urls() {
+        let https = Some("https://github.com/pop-os/hello.git");
+        let scp = Some("git@github.com:pop-os/hello");
+
+        assert_eq!(
+            submodule_url(https, "https://gitlab.com/x/y")
+                .as_ref()
+                .map(String::as_str),
+            Some("https://gitlab.com/x/y")
+        );
+        assert_eq!(
+            submodule_url(None, "git@github.com:x/y")
+                .as_ref()
+                .map(String::as_str),
+            Some("git@github.com:x/y")
+        );
+
+        let cases = [
+            (https, "./sub", "https://github.com/pop-os/hello.git/sub"),
+            (https, "../gtk", "https://github.com/pop-os/gtk"),
+            (https, "../../other/gtk", "https://github.com/other/gtk"),
+            (
+                Some("https://github.com/pop-os/hello/"),
+                "../gtk",
+                "https://github.com/pop-os/gtk",
+            ),
+            (scp, "../gtk", "git@github.com:pop-os/gtk"),
+            (scp, "../../other/gtk", "git@github.com:other/gtk"),
+        ];
+
+        for &(base, url, expected) in &cases {
+            assert_eq!(
+                submodule_url(base, url).as_ref().map(String::as_str),
+                Some(expected),
+                "{} relative to {:?}",
+                url,
+                base
+            );
+        }
+
+        assert_eq!(submodule_url(None, "../gtk"), None);
+        assert_eq!(submodule_url(Some("hello"), "../gtk"), None);
+    }
+}
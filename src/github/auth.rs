@@ -0,0 +1,213 @@
+//! Credentials which authenticate requests to the GitHub API.
+//!
+//! Credentials are only ever sent in the `Authorization` header. Every secret that is loaded
+//! or minted is registered with `redact`, which scrubs them from errors and logs. A secret
+//! which is minted again replaces the one before it, so only live secrets are kept.
+
+use crate::config::ConfigGitHubAuth;
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::Client;
+use std::{borrow::Cow, collections::HashMap, env, fs, io, path::Path, sync::Mutex};
+
+/// Read when no credentials are configured.
+const DEFAULT_TOKEN_PATH: &str = ".github_token";
+
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to read GitHub token from {:?}", _0)]
+    TokenFile(Box<Path>, #[source] io::Error),
+    #[error("GitHub token variable {} is not set", _0)]
+    TokenEnv(Box<str>),
+    #[error("failed to read private key of GitHub App from {:?}", _0)]
+    KeyFile(Box<Path>, #[source] io::Error),
+    #[error("private key of GitHub App is not a valid RSA key")]
+    Key(#[source] jsonwebtoken::errors::Error),
+    #[error("failed to sign JWT of GitHub App")]
+    Sign(#[source] jsonwebtoken::errors::Error),
+    #[error("failed to create installation token of GitHub App")]
+    Installation(#[source] reqwest::Error),
+}
+
+/// How requests to the GitHub API are authenticated.
+pub enum Credentials {
+    Anonymous,
+    /// A personal access token, or any other long-lived token.
+    Token(Box<str>),
+    /// Installation tokens of a GitHub App, which are minted when they are needed.
+    App(App),
+}
+
+pub struct App {
+    id: u64,
+    installation_id: u64,
+    key: EncodingKey,
+    token: Mutex<Option<InstallationToken>>,
+}
+
+#[derive(Clone, Deserialize)]
+struct InstallationToken {
+    token: Box<str>,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct Claims {
+    iat: i64,
+    exp: i64,
+    iss: u64,
+}
+
+static CREDENTIALS: OnceCell<Credentials> = OnceCell::new();
+
+/// The kinds of secrets, of which only the latest of each is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Secret {
+    /// A long-lived token of the config.
+    Token,
+    /// The JWT of a GitHub App, which is signed for each installation token.
+    Jwt,
+    /// The current installation token of a GitHub App.
+    Installation,
+    /// The secret which webhook deliveries are signed with.
+    Webhook,
+}
+
+static SECRETS: Lazy<Mutex<HashMap<Secret, Box<str>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl Credentials {
+    /// Loads the credentials of the config.
+    ///
+    /// A GitHub App takes precedence over a token variable, which takes precedence over a
+    /// token file. Without any of these, the token in `.github_token` is used if it exists.
+    pub fn from_config(auth: &ConfigGitHubAuth) -> Result<Self, Error> {
+        if let Some(app) = auth.app.as_ref() {
+            let path = Path::new(&*app.private_key);
+            let pem = fs::read(path).map_err(|why| Error::KeyFile(path.into(), why))?;
+            let key = EncodingKey::from_rsa_pem(&pem).map_err(Error::Key)?;
+
+            return Ok(Credentials::App(App {
+                id: app.id,
+                installation_id: app.installation_id,
+                key,
+                token: Mutex::new(None),
+            }));
+        }
+
+        if let Some(var) = auth.token_env.as_ref() {
+            let token = env::var(&**var).map_err(|_| Error::TokenEnv(var.clone()))?;
+            return Ok(Credentials::token(&token));
+        }
+
+        let path = match auth.token_file.as_ref() {
+            Some(path) => Path::new(&**path),
+            None if Path::new(DEFAULT_TOKEN_PATH).exists() => Path::new(DEFAULT_TOKEN_PATH),
+            None => return Ok(Credentials::Anonymous),
+        };
+
+        let token = fs::read_to_string(path).map_err(|why| Error::TokenFile(path.into(), why))?;
+        Ok(Credentials::token(&token))
+    }
+
+    fn token(token: &str) -> Self {
+        let token = token.trim();
+        register_secret(Secret::Token, token);
+        Credentials::Token(token.into())
+    }
+}
+
+/// Sets the credentials of every request. Only the first credentials that are set are used.
+pub fn set(credentials: Credentials) {
+    let _ = CREDENTIALS.set(credentials);
+}
+
+/// Fetches the value of the `Authorization` header, minting an installation token if needed.
+pub(super) async fn authorization(client: &Client) -> Result<Option<String>, Error> {
+    match CREDENTIALS.get() {
+        Some(Credentials::Token(token)) => Ok(Some(["token ", token].concat())),
+        Some(Credentials::App(app)) => {
+            let token = app.installation_token(client).await?;
+            Ok(Some(["token ", &token].concat()))
+        }
+        Some(Credentials::Anonymous) | None => Ok(None),
+    }
+}
+
+impl App {
+    /// Fetches the current installation token, or mints a new one if it is about to expire.
+    async fn installation_token(&self, client: &Client) -> Result<Box<str>, Error> {
+        let cached = self.token.lock().expect("token mutex poisoned").clone();
+        if let Some(cached) = cached {
+            if cached.expires_at > Utc::now() + Duration::minutes(1) {
+                return Ok(cached.token);
+            }
+        }
+
+        let now = Utc::now();
+        let claims = Claims {
+            // Allows for clock drift between this machine and GitHub.
+            iat: (now - Duration::seconds(60)).timestamp(),
+            exp: (now + Duration::minutes(9)).timestamp(),
+            iss: self.id,
+        };
+
+        let jwt = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)
+            .map_err(Error::Sign)?;
+
+        register_secret(Secret::Jwt, &jwt);
+
+        let url = [
+            "https://api.github.com/app/installations/",
+            &self.installation_id.to_string(),
+            "/access_tokens",
+        ]
+        .concat();
+
+        let token = client
+            .post(&*url)
+            .header("accept", "application/vnd.github.machine-man-preview+json")
+            .header("authorization", ["Bearer ", &jwt].concat())
+            .send()
+            .await
+            .map_err(Error::Installation)?
+            .error_for_status()
+            .map_err(Error::Installation)?
+            .json::<InstallationToken>()
+            .await
+            .map_err(Error::Installation)?;
+
+        register_secret(Secret::Installation, &token.token);
+        *self.token.lock().expect("token mutex poisoned") = Some(token.clone());
+
+        Ok(token.token)
+    }
+}
+
+/// Registers a secret, so that it is removed from messages by `redact`, replacing the
+/// previous secret of its kind.
+pub fn register_secret(kind: Secret, secret: &str) {
+    let mut secrets = SECRETS.lock().expect("secrets mutex poisoned");
+
+    if secret.is_empty() {
+        secrets.remove(&kind);
+    } else {
+        secrets.insert(kind, secret.into());
+    }
+}
+
+/// Replaces every known secret in a message.
+pub fn redact(message: &str) -> Cow<str> {
+    let secrets = SECRETS.lock().expect("secrets mutex poisoned");
+
+    let mut message = Cow::Borrowed(message);
+    for secret in secrets.values() {
+        if message.contains(&**secret) {
+            message = Cow::Owned(message.replace(&**secret, REDACTED));
+        }
+    }
+
+    message
+}
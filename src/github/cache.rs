@@ -0,0 +1,43 @@
+//! Responses of the GitHub API, stored on disk with their ETags for conditional requests.
+//!
+//! Responses to conditional requests which return `304 Not Modified` are not counted
+//! against the rate limit, so unchanged organizations and branches cost nothing to re-check.
+//! Responses are stored in the `github` directory of the build directory.
+
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+/// A response to a GET request, and the link to the next page of its results.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CachedResponse {
+    pub etag: Box<str>,
+    pub next: Option<Box<str>>,
+    pub body: String,
+}
+
+impl CachedResponse {
+    /// Loads the cached response for a URL, if it exists and is readable.
+    pub async fn load(dir: &Path, url: &str) -> Option<Self> {
+        let data = fs::read(path(dir, url)).await.ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Stores the response of a URL.
+    pub async fn store(&self, dir: &Path, url: &str) -> io::Result<()> {
+        let data = serde_json::to_vec(self)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+        fs::create_dir_all(dir).await?;
+        fs::write(path(dir, url), data).await
+    }
+}
+
+/// Responses are stored by the hash of their URL.
+fn path(dir: &Path, url: &str) -> PathBuf {
+    let name = format!("{:x}.json", Sha256::digest(url.as_bytes()));
+    dir.join(name)
+}
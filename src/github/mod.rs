@@ -0,0 +1,491 @@
+pub mod auth;
+mod cache;
+
+use self::cache::CachedResponse;
+use chrono::{DateTime, Utc};
+use reqwest::{header::HeaderMap, Client, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::timer::delay_for;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to authenticate")]
+    Auth(#[from] auth::Error),
+    #[error("failed to deserialize JSON response of {}", url)]
+    Deserialize {
+        url: Box<str>,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("failed to get {}", url)]
+    Get {
+        url: Box<str>,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("failed to create check run for {}", repo)]
+    CheckRun {
+        repo: Box<str>,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("failed to set status for {}", repo)]
+    Status {
+        repo: Box<str>,
+        #[source]
+        source: reqwest::Error,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Repo {
+    pub name: Box<str>,
+    pub url: Box<str>,
+    pub pushed_at: DateTime<Utc>,
+    #[serde(default)]
+    pub archived: bool,
+    #[serde(default)]
+    pub fork: bool,
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub topics: Vec<Box<str>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Branch {
+    pub name: Box<str>,
+    pub commit: Commit,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Commit {
+    pub sha: Box<str>,
+}
+
+pub async fn organization_repos(
+    client: Arc<Client>,
+    cache: &Path,
+    org: &str,
+) -> Result<Vec<Repo>, Error> {
+    fetch_all::<Repo>(&client, cache, &["/orgs/", org, "/repos"].concat()).await
+}
+
+pub async fn repository(
+    client: Arc<Client>,
+    cache: &Path,
+    owner: &str,
+    repo: &str,
+) -> Result<Repo, Error> {
+    let url = ["https://api.github.com/repos/", owner, "/", repo].concat();
+    let response = get(&client, cache, &url).await?;
+    parse(&url, &response.body)
+}
+
+pub async fn repository_branches(
+    client: Arc<Client>,
+    cache: &Path,
+    owner: &str,
+    repo: &str,
+) -> Result<Vec<Branch>, Error> {
+    fetch_all::<Branch>(
+        &client,
+        cache,
+        &["/repos/", owner, "/", repo, "/branches"].concat(),
+    )
+    .await
+}
+
+/// A tag of a repository, and the commit that it points to.
+#[derive(Debug, Deserialize)]
+pub struct Tag {
+    pub name: Box<str>,
+    pub commit: Commit,
+}
+
+pub async fn repository_tags(
+    client: Arc<Client>,
+    cache: &Path,
+    owner: &str,
+    repo: &str,
+) -> Result<Vec<Tag>, Error> {
+    let path = ["/repos/", owner, "/", repo, "/tags"].concat();
+    fetch_all::<Tag>(&client, cache, &path).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PullRequest {
+    pub number: u64,
+    pub head: PullRequestRef,
+    pub base: PullRequestRef,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PullRequestRef {
+    #[serde(rename = "ref")]
+    pub reference: Box<str>,
+    pub sha: Box<str>,
+}
+
+/// Lists the open pull requests of a repository.
+pub async fn repository_pulls(
+    client: Arc<Client>,
+    cache: &Path,
+    owner: &str,
+    repo: &str,
+) -> Result<Vec<PullRequest>, Error> {
+    let path = ["/repos/", owner, "/", repo, "/pulls"].concat();
+    fetch_all::<PullRequest>(&client, cache, &path).await
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusContext<'a> {
+    pub context: &'a str,
+    pub description: &'a str,
+    /// One of `pending`, `success`, `failure`, or `error`.
+    pub state: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target_url: Option<&'a str>,
+}
+
+pub async fn status(
+    client: &Client,
+    owner: &str,
+    repo: &str,
+    id: &str,
+    context: &StatusContext<'_>,
+) -> Result<(), Error> {
+    let url = [
+        "https://api.github.com/repos/",
+        owner,
+        "/",
+        repo,
+        "/statuses/",
+        id,
+    ]
+    .concat();
+
+    let status_error = |source| Error::Status {
+        repo: [owner, "/", repo].concat().into(),
+        source,
+    };
+
+    let request = client
+        .post(&*url)
+        .header("accept", "application/vnd.github.v3+json")
+        .header("content-type", "application/json")
+        .json(context);
+
+    authorize(client, request)
+        .await?
+        .send()
+        .await
+        .map_err(status_error)?
+        .error_for_status()
+        .map_err(status_error)?;
+
+    Ok(())
+}
+
+/// A completed check run, as accepted by the GitHub Checks API.
+#[derive(Debug, Serialize)]
+pub struct CheckRun<'a> {
+    pub name: &'a str,
+    pub head_sha: &'a str,
+    pub status: &'a str,
+    /// One of `success`, `failure`, `neutral`, `cancelled`, `timed_out`, or `action_required`.
+    pub conclusion: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details_url: Option<&'a str>,
+    pub output: CheckOutput<'a>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckOutput<'a> {
+    pub title: &'a str,
+    pub summary: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<&'a str>,
+    pub annotations: &'a [CheckAnnotation],
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckAnnotation {
+    pub path: Box<str>,
+    pub start_line: u32,
+    pub end_line: u32,
+    /// One of `notice`, `warning`, or `failure`.
+    pub annotation_level: &'static str,
+    pub message: Box<str>,
+}
+
+/// Creates a check run on a commit.
+///
+/// The Checks API only accepts installation tokens of GitHub Apps.
+pub async fn check_run(
+    client: &Client,
+    owner: &str,
+    repo: &str,
+    check_run: &CheckRun<'_>,
+) -> Result<(), Error> {
+    let url = [
+        "https://api.github.com/repos/",
+        owner,
+        "/",
+        repo,
+        "/check-runs",
+    ]
+    .concat();
+
+    let check_run_error = |source| Error::CheckRun {
+        repo: [owner, "/", repo].concat().into(),
+        source,
+    };
+
+    let request = client
+        .post(&*url)
+        .header("accept", "application/vnd.github.antiope-preview+json")
+        .header("content-type", "application/json")
+        .json(check_run);
+
+    authorize(client, request)
+        .await?
+        .send()
+        .await
+        .map_err(check_run_error)?
+        .error_for_status()
+        .map_err(check_run_error)?;
+
+    Ok(())
+}
+
+/// Adds the credentials of the client to a request.
+async fn authorize(client: &Client, request: RequestBuilder) -> Result<RequestBuilder, Error> {
+    Ok(match auth::authorization(client).await? {
+        Some(authorization) => request.header("authorization", authorization),
+        None => request,
+    })
+}
+
+/// Fetches every page of a paginated resource, following the `Link` header of each page.
+async fn fetch_all<T: DeserializeOwned>(
+    client: &Client,
+    cache: &Path,
+    path: &str,
+) -> Result<Vec<T>, Error> {
+    let mut data = Vec::new();
+    let mut next = Some(["https://api.github.com", path, "?per_page=100"].concat());
+
+    while let Some(url) = next.take() {
+        let response = get(client, cache, &url).await?;
+        data.extend(parse::<Vec<T>>(&url, &response.body)?);
+        next = response.next.map(String::from);
+    }
+
+    Ok(data)
+}
+
+fn parse<T: DeserializeOwned>(url: &str, body: &str) -> Result<T, Error> {
+    serde_json::from_str(body).map_err(|source| Error::Deserialize {
+        url: url.into(),
+        source,
+    })
+}
+
+/// The time at which the rate limit resets, if it has been exhausted.
+static RATE_LIMIT_RESET: AtomicI64 = AtomicI64::new(0);
+
+/// Sends a conditional GET request, using the response cached in `cache` if it was not
+/// modified.
+///
+/// Waits for the rate limit to reset when it has been exhausted, and then retries.
+async fn get(client: &Client, cache: &Path, url: &str) -> Result<CachedResponse, Error> {
+    let get_error = |source| Error::Get {
+        url: url.into(),
+        source,
+    };
+
+    let cached = CachedResponse::load(cache, url).await;
+
+    loop {
+        wait_for_rate_limit().await;
+
+        // The topics of repositories are only included by the mercy preview.
+        let request = client
+            .get(url)
+            .header("accept", "application/vnd.github.mercy-preview+json");
+
+        let mut request = authorize(client, request).await?;
+
+        if let Some(cached) = cached.as_ref() {
+            request = request.header("if-none-match", &*cached.etag);
+        }
+
+        let response = request.send().await.map_err(get_error)?;
+        let rate_limited = record_rate_limit(&response);
+
+        match response.status() {
+            StatusCode::NOT_MODIFIED if cached.is_some() => {
+                return Ok(cached.expect("cached response is missing"));
+            }
+            StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS if rate_limited => continue,
+            _ => (),
+        }
+
+        let response = response.error_for_status().map_err(get_error)?;
+        let headers = response.headers();
+
+        let etag = header(headers, "etag").map(Box::from);
+        let next = header(headers, "link").and_then(next_link).map(Box::from);
+        let body = response.text().await.map_err(get_error)?;
+
+        let response = CachedResponse {
+            etag: etag.unwrap_or_default(),
+            next,
+            body,
+        };
+
+        if !response.etag.is_empty() {
+            if let Err(why) = response.store(cache, url).await {
+                warn!("failed to cache response of {}: {}", url, why);
+            }
+        }
+
+        return Ok(response);
+    }
+}
+
+/// Records when the rate limit resets, returning true if it is exhausted.
+fn record_rate_limit(response: &Response) -> bool {
+    match rate_limit_reset(response.headers(), Utc::now().timestamp()) {
+        Some(reset) => {
+            RATE_LIMIT_RESET.store(reset, Ordering::SeqCst);
+            true
+        }
+        None => false,
+    }
+}
+
+/// The time at which an exhausted rate limit resets, or `None` if it is not exhausted.
+fn rate_limit_reset(headers: &HeaderMap, now: i64) -> Option<i64> {
+    let number = |name| header(headers, name).and_then(|value| value.parse::<i64>().ok());
+
+    if number("x-ratelimit-remaining") == Some(0) {
+        number("x-ratelimit-reset")
+    } else if let Some(seconds) = number("retry-after") {
+        Some(now + seconds)
+    } else {
+        None
+    }
+}
+
+async fn wait_for_rate_limit() {
+    let reset = RATE_LIMIT_RESET.load(Ordering::SeqCst);
+    let now = Utc::now().timestamp();
+
+    if reset > now {
+        let seconds = (reset - now) as u64 + 1;
+        warn!("GitHub rate limit exhausted: waiting {} seconds", seconds);
+        delay_for(Duration::from_secs(seconds)).await;
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Finds the URL of the next page in a `Link` header.
+fn next_link(link: &str) -> Option<&str> {
+    link.split(',').find_map(|entry| {
+        let mut parts = entry.split(';');
+        let url = parts.next()?.trim();
+
+        if parts.any(|param| param.trim() == "rel=\"next\"") {
+            Some(url.trim_start_matches('<').trim_end_matches('>'))
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    const NOW: i64 = 1_570_000_000;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+
+        headers
+    }
+
+    #[test]
+    fn finds_next_links() {
+        let first = "<https://api.github.com/orgs/pop-os/repos?page=3>; rel=\"next\", \
+                     <https://api.github.com/orgs/pop-os/repos?page=9>; rel=\"last\"";
+        assert_eq!(
+            next_link(first),
+            Some("https://api.github.com/orgs/pop-os/repos?page=3")
+        );
+
+        let last = "<https://api.github.com/orgs/pop-os/repos?page=1>; rel=\"prev\", \
+                    <https://api.github.com/orgs/pop-os/repos?page=1>; rel=\"first\", \
+                    <https://api.github.com/orgs/pop-os/repos?page=3>; rel=\"next\"";
+        assert_eq!(
+            next_link(last),
+            Some("https://api.github.com/orgs/pop-os/repos?page=3")
+        );
+
+        let spaced = "<https://api.github.com/orgs/pop-os/repos?page=2> ;  rel=\"next\" ";
+        assert_eq!(
+            next_link(spaced),
+            Some("https://api.github.com/orgs/pop-os/repos?page=2")
+        );
+    }
+
+    #[test]
+    fn finds_no_link_on_the_last_page() {
+        let absent = "<https://api.github.com/orgs/pop-os/repos?page=8>; rel=\"prev\", \
+                      <https://api.github.com/orgs/pop-os/repos?page=1>; rel=\"first\"";
+        assert_eq!(next_link(absent), None);
+        assert_eq!(next_link(""), None);
+    }
+
+    #[test]
+    fn resets_exhausted_rate_limits() {
+        let exhausted = headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "1570000060"),
+        ]);
+        assert_eq!(rate_limit_reset(&exhausted, NOW), Some(1_570_000_060));
+
+        let retry = headers(&[("x-ratelimit-remaining", "10"), ("retry-after", "30")]);
+        assert_eq!(rate_limit_reset(&retry, NOW), Some(NOW + 30));
+
+        let retry = headers(&[("retry-after", "30")]);
+        assert_eq!(rate_limit_reset(&retry, NOW), Some(NOW + 30));
+    }
+
+    #[test]
+    fn ignores_remaining_rate_limits() {
+        let remaining = headers(&[
+            ("x-ratelimit-remaining", "4999"),
+            ("x-ratelimit-reset", "1570000060"),
+        ]);
+        assert_eq!(rate_limit_reset(&remaining, NOW), None);
+        assert_eq!(rate_limit_reset(&HeaderMap::new(), NOW), None);
+    }
+}
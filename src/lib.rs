@@ -1,6 +1,8 @@
 #[macro_use]
 extern crate anyhow;
 #[macro_use]
+extern crate async_trait;
+#[macro_use]
 extern crate cascade;
 #[macro_use]
 extern crate futures;
@@ -13,16 +15,20 @@ extern crate smart_default;
 #[macro_use]
 extern crate thiserror;
 
-// pub mod apt;
-pub mod blacklist;
+pub mod apt;
+pub mod checks;
 pub mod collate;
 pub mod config;
 pub mod dpkg;
 pub mod errors;
 pub mod fetcher;
+pub mod filter;
+pub mod forge;
 pub mod git;
 pub mod github;
 pub mod misc;
+pub mod state;
+pub mod webhook;
 
 use std::cell::RefCell;
 
@@ -4,59 +4,675 @@ extern crate futures;
 extern crate log;
 
 use pop_ci::{
-    blacklist, collate,
-    config::{Config, ConfigOrganization},
+    apt, collate,
+    config::Config,
     dpkg,
-    fetcher::{Fetcher, Repository},
+    errors::format_error,
+    fetcher::{self, Fetcher, Repository},
+    filter::Filters,
+    forge::{Fixture, Forge, GitHub},
     git::GitTar,
-    misc, Error, STRING_BUF,
+    github::auth::{self, Credentials, Secret},
+    misc,
+    state::State,
+    webhook::{self, Event, EventKind},
+    Error,
 };
 
 use anyhow::Context;
+use chrono::{Duration, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use futures::prelude::*;
 use reqwest::Client;
-use std::collections::HashMap;
-use std::{env, error::Error as StdError, fmt::Write, ops::Deref, sync::Arc};
+use std::collections::{HashMap, HashSet};
+use std::{env, error::Error as StdError, net::SocketAddr, ops::Deref, path::Path, sync::Arc};
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tokio::{
-    fs::{self, File},
-    io::AsyncWriteExt,
-};
 
-// Fetch the blacklist entries while cleaning up the schroot sessions
-async fn startup<'a>(
-    config: &Config,
-    buffer: &'a mut String,
-) -> anyhow::Result<(File, Vec<(&'a str, &'a str)>)> {
-    let blacklist_path = config.dirs.build.join("blacklist");
+/// How far along the pipeline a subcommand should take each repository.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Stage {
+    /// Fetch repositories and check out their branches.
+    Fetch,
+    /// Collate the build queue, and print what would be built.
+    Plan,
+    /// Build source packages.
+    Source,
+    /// Build source packages, and then binary packages from them.
+    Binary,
+    /// Build all packages, and then publish them to apt repositories.
+    Publish,
+}
+
+/// Repositories that a pipeline run fetches.
+#[derive(Debug, Clone, Copy)]
+enum Selection<'a> {
+    /// Every configured repository, which replaces the packages that were rebuilt in the apt
+    /// repositories, and removes those of branches which are no longer built.
+    All,
+    /// A single repository of the forge, which only replaces its own packages when published.
+    Repository { owner: &'a str, name: &'a str },
+    /// A single repository of the forge, which is fetched without building anything, so that
+    /// its packages are removed from a pocket that it is no longer built for.
+    Withdrawal {
+        owner: &'a str,
+        name: &'a str,
+        pocket: &'a str,
+    },
+}
+
+impl<'a> Selection<'a> {
+    /// Whether a repository's packages are removed from a pocket's codename when it is no
+    /// longer built for it.
+    ///
+    /// Branch filters leave out the targets of the other branches, so nothing is pruned by runs
+    /// which have them.
+    fn prunes(self, filters: &Filters, pocket: &str, codename: &str) -> bool {
+        match self {
+            Selection::All => {
+                filters.branches.is_empty() && filters.pocket(pocket) && filters.series(codename)
+            }
+            Selection::Withdrawal {
+                pocket: withdrawn, ..
+            } => withdrawn == pocket && filters.series(codename),
+            Selection::Repository { .. } => false,
+        }
+    }
+}
+
+/// Changes to the apt repositories, which are made once every repository has been processed.
+#[derive(Debug)]
+enum Publication {
+    /// Packages which replace those of a repository in a pocket's codename.
+    Pool(PoolEntry),
+    /// The pockets and codenames that a repository is built for.
+    Targets {
+        package: Box<str>,
+        targets: HashSet<(Box<str>, Box<str>)>,
+    },
+}
+
+/// Packages of a repository which were built for a pocket's codename.
+#[derive(Debug)]
+struct PoolEntry {
+    pocket: Box<str>,
+    codename: Box<str>,
+    package: Box<str>,
+    files: Vec<Box<Path>>,
+}
+
+fn cli() -> App<'static, 'static> {
+    let filter = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .help(help)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .global(true)
+    };
+
+    App::new("pop-ci")
+        .about("Builds Debian packages from the repositories of GitHub organizations")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(filter("repo", "only process repositories with this name"))
+        .arg(filter("branch", "only process branches with this name"))
+        .arg(filter("series", "only build for this series codename"))
+        .arg(filter("pocket", "only build for this pocket"))
+        .arg(filter("arch", "only build binaries for this architecture"))
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("build and publish repositories when GitHub webhooks report changes"),
+        )
+        .subcommand(
+            SubCommand::with_name("repos")
+                .about("list the repositories that would be processed, and why others are not"),
+        )
+        .subcommand(SubCommand::with_name("fetch").about("fetch repositories and their branches"))
+        .subcommand(SubCommand::with_name("plan").about("show what would be built"))
+        .subcommand(SubCommand::with_name("source").about("build source packages"))
+        .subcommand(SubCommand::with_name("binary").about("build source and binary packages"))
+        .subcommand(SubCommand::with_name("publish").about("publish apt repositories"))
+        .subcommand(SubCommand::with_name("status").about("show the state of previous builds"))
+        .subcommand(blacklist_cli())
+}
+
+fn blacklist_cli() -> App<'static, 'static> {
+    let commit_args = || {
+        vec![
+            Arg::with_name("REPO")
+                .help("name of the repository")
+                .required(true),
+            Arg::with_name("COMMIT")
+                .help("commit hash, which may be abbreviated when removing")
+                .required(true),
+        ]
+    };
+
+    SubCommand::with_name("blacklist")
+        .about("manage commits which are not to be built")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("list").about("list blacklisted commits"))
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("blacklist a commit for a series, or an arch of a series")
+                .args(&commit_args())
+                .arg(
+                    Arg::with_name("SERIES")
+                        .help("series codename")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("ARCH")
+                        .help("arch to blacklist, where `source` blacklists every arch")
+                        .default_value("source"),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .help("hours until the entry expires")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("reason")
+                        .long("reason")
+                        .help("why the commit is blacklisted")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("remove")
+                .about("remove the entries of a commit")
+                .args(&commit_args())
+                .arg(Arg::with_name("SERIES").help("only remove entries of this series"))
+                .arg(Arg::with_name("ARCH").help("only remove entries of this arch")),
+        )
+        .subcommand(SubCommand::with_name("expire").about("purge entries which have expired"))
+}
+
+fn filters_from(matches: &ArgMatches) -> Filters {
+    let values = |name: &str| {
+        matches
+            .values_of(name)
+            .map(|values| values.map(Box::from).collect())
+            .unwrap_or_default()
+    };
+
+    Filters {
+        repos: values("repo"),
+        branches: values("branch"),
+        series: values("series"),
+        pockets: values("pocket"),
+        archs: values("arch"),
+    }
+}
 
+// Open the build state database while cleaning up the schroot sessions
+async fn startup(config: &Config, stage: Stage) -> anyhow::Result<State> {
     let session_cleanup = async {
+        if stage < Stage::Binary {
+            return Ok(());
+        }
+
         misc::check_call("schroot", &["--end-session", "--all-sessions"], None)
             .await
             .context("failed to clean up schroot sessions")
     };
 
-    let blacklist = blacklist::fetch(buffer, &blacklist_path, config.retry);
+    session_cleanup.await?;
+
+    let state = open_state(config)?;
+
+    // Only runs which build anything own the stages that are still building.
+    if stage >= Stage::Source {
+        let interrupted = state
+            .interrupt()
+            .context("failed to mark interrupted builds")?;
+        info!("marked {} interrupted builds", interrupted);
+    }
+
+    if config.retry {
+        let cleared = state
+            .blacklist_clear()
+            .context("failed to clear the blacklist")?;
+        info!("cleared {} entries from the blacklist", cleared);
+    } else {
+        let expired = state
+            .blacklist_expire()
+            .context("failed to expire blacklist entries")?;
+        info!("expired {} entries from the blacklist", expired);
+    }
 
-    try_join!(session_cleanup, blacklist).map(|r| r.1)
+    Ok(state)
+}
+
+fn open_state(config: &Config) -> anyhow::Result<State> {
+    State::open(&config.dirs.build.join("state.sqlite3"))
+        .context("failed to open the build state database")
 }
 
 async fn main_() -> Result<(), anyhow::Error> {
+    let matches = cli().get_matches();
+    let filters = filters_from(&matches);
     let config = Arc::new(Config::new()?);
+
+    match matches.subcommand() {
+        ("fetch", _) => run(config, &filters, Stage::Fetch, Selection::All).await,
+        ("plan", _) => run(config, &filters, Stage::Plan, Selection::All).await,
+        ("source", _) => run(config, &filters, Stage::Source, Selection::All).await,
+        ("binary", _) => run(config, &filters, Stage::Binary, Selection::All).await,
+        ("publish", _) => run(config, &filters, Stage::Publish, Selection::All).await,
+        ("serve", _) => serve(config, &filters).await,
+        ("repos", _) => repos(&config, &filters).await,
+        ("status", _) => status(&config, &filters).await,
+        ("blacklist", Some(matches)) => blacklist(&config, &filters, matches),
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
+
+/// Lists, adds, removes, and expires blacklist entries.
+fn blacklist(config: &Config, filters: &Filters, matches: &ArgMatches) -> anyhow::Result<()> {
+    let state = open_state(config)?;
+
+    match matches.subcommand() {
+        ("list", _) => {
+            register_secrets(config);
+
+            let now = Utc::now();
+            for entry in state.blacklist(filters)? {
+                let expires = match entry.expires {
+                    Some(time) if time <= now => "expired".to_owned(),
+                    Some(time) => ["expires ", &time.to_rfc3339()].concat(),
+                    None => "never expires".to_owned(),
+                };
+
+                print_redacted(&format!(
+                    "{} {} {} {} ({})",
+                    entry.repo, entry.commit, entry.series, entry.arch, expires
+                ));
+
+                if let Some(reason) = entry.reason.as_ref() {
+                    print_redacted(&["    reason: ", reason].concat());
+                }
+            }
+        }
+        ("add", Some(matches)) => {
+            let expires = match matches.value_of("ttl") {
+                Some(hours) => {
+                    let hours = hours
+                        .parse::<i64>()
+                        .context("ttl must be a number of hours")?;
+                    Some(Utc::now() + Duration::hours(hours))
+                }
+                None => None,
+            };
+
+            state.blacklist_add(
+                matches.value_of("REPO").unwrap(),
+                matches.value_of("COMMIT").unwrap(),
+                matches.value_of("SERIES").unwrap(),
+                matches.value_of("ARCH").unwrap(),
+                matches.value_of("reason"),
+                expires,
+            )?;
+        }
+        ("remove", Some(matches)) => {
+            let removed = state.blacklist_remove(
+                matches.value_of("REPO").unwrap(),
+                matches.value_of("COMMIT").unwrap(),
+                matches.value_of("SERIES"),
+                matches.value_of("ARCH"),
+            )?;
+
+            println!("removed {} entries", removed);
+        }
+        ("expire", _) => {
+            let expired = state.blacklist_expire()?;
+            println!("expired {} entries", expired);
+        }
+        _ => unreachable!("clap requires a subcommand"),
+    }
+
+    Ok(())
+}
+
+/// Prints the recorded state of every build stage which the filters accept.
+async fn status(config: &Config, filters: &Filters) -> anyhow::Result<()> {
+    let state = open_state(config)?;
+    register_secrets(config);
+
+    for record in state.query(filters)? {
+        let time = record
+            .finished
+            .or(record.started)
+            .map(|time| time.to_rfc3339())
+            .unwrap_or_default();
+
+        print_redacted(&format!(
+            "{} {} {} {} {}/{} {} {}",
+            record.repo,
+            &record.commit[..7.min(record.commit.len())],
+            record.series,
+            record.pocket,
+            record.stage.as_str(),
+            record.arch,
+            record.status.as_str(),
+            time
+        ));
+
+        if let Some(reason) = record.reason.as_ref() {
+            if let Some(line) = reason.lines().next() {
+                print_redacted(&["    reason: ", line].concat());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds and publishes the repositories that webhook deliveries report changes to.
+///
+/// Events are handled one at a time. Only the branch or pull request of an event is built,
+/// and only its pocket is republished. Deleted branches and closed pull requests have their
+/// packages removed from their pocket, unless another branch is still built for it.
+async fn serve(config: Arc<Config>, filters: &Filters) -> anyhow::Result<()> {
+    let secret = std::fs::read_to_string(&*config.webhook.secret_file)
+        .with_context(|| format!("failed to read {}", config.webhook.secret_file))?;
+    let secret = secret.trim();
+    anyhow::ensure!(!secret.is_empty(), "webhook secret is empty");
+    auth::register_secret(Secret::Webhook, secret);
+
+    let address = config
+        .webhook
+        .listen
+        .parse::<SocketAddr>()
+        .with_context(|| format!("invalid webhook address: {}", config.webhook.listen))?;
+
+    let (events_tx, mut events_rx) = unbounded_channel();
+
+    let server = webhook::serve(address, secret.into(), events_tx);
+
+    let builder = async {
+        while let Some(event) = events_rx.next().await {
+            let event: Event = event;
+
+            if !configured(&config, &event.owner, &event.repo) || !filters.repo(&event.repo) {
+                info!(
+                    "{}/{}: ignoring event of a repository which is not built",
+                    event.owner, event.repo
+                );
+                continue;
+            }
+
+            let target = match config.branches.map(&event.branch) {
+                Some(target) if filters.branch(&event.branch) => target,
+                _ => {
+                    info!(
+                        "{}/{} branch {}: ignoring event of a branch which is not built",
+                        event.owner, event.repo, event.branch
+                    );
+                    continue;
+                }
+            };
+
+            // Pull requests are built for the series of their base branch, in their own pocket.
+            let pocket = match (event.kind, event.pull_request) {
+                (EventKind::PullRequest, Some(number))
+                | (EventKind::PullRequestClosed, Some(number))
+                    if config.pull_requests.build =>
+                {
+                    config.pull_requests.pocket(number)
+                }
+                (EventKind::PullRequest, _) | (EventKind::PullRequestClosed, _) => {
+                    info!(
+                        "{}/{}: ignoring pull request event, as pull requests are not built",
+                        event.owner, event.repo
+                    );
+                    continue;
+                }
+                _ => target.pocket,
+            };
+
+            if !filters.pocket(&pocket) {
+                info!(
+                    "{}/{} pocket {}: ignoring event of a pocket which is not built",
+                    event.owner, event.repo, pocket
+                );
+                continue;
+            }
+
+            let (owner, name) = (&*event.owner, &*event.repo);
+
+            let result = match event.kind {
+                EventKind::Delete | EventKind::PullRequestClosed => {
+                    let selection = Selection::Withdrawal {
+                        owner,
+                        name,
+                        pocket: &pocket,
+                    };
+
+                    run(config.clone(), filters, Stage::Publish, selection).await
+                }
+                EventKind::Push | EventKind::Create | EventKind::PullRequest => {
+                    let filters = Filters {
+                        branches: vec![event.branch.clone()],
+                        pockets: vec![pocket.clone()],
+                        ..filters.clone()
+                    };
+
+                    let selection = Selection::Repository { owner, name };
+                    run(config.clone(), &filters, Stage::Publish, selection).await
+                }
+            };
+
+            if let Err(why) = result {
+                let why: Box<dyn StdError + 'static> = Box::from(why);
+                format_error(&*why, |why| {
+                    error!("{}/{}: failed to build: {}", event.owner, event.repo, why)
+                });
+            }
+        }
+    };
+
+    let (result, ()) = join!(server, builder);
+    result.context("webhook server stopped")
+}
+
+/// Whether a repository of the forge belongs to a configured organization, or is configured.
+fn configured(config: &Config, owner: &str, name: &str) -> bool {
+    config
+        .github
+        .organizations
+        .iter()
+        .any(|org| &*org.name == owner)
+        || config
+            .github
+            .repos
+            .iter()
+            .any(|repo| &**repo == [owner, "/", name].concat())
+}
+
+/// Lists the repositories of every organization, and the reason that each is not selected.
+async fn repos(config: &Config, filters: &Filters) -> anyhow::Result<()> {
     let client = Arc::new(Client::new());
+    set_credentials(config)?;
+
+    match config.fixtures.as_ref() {
+        Some(fixtures) => {
+            let forge = Fixture::new(config.dirs.base.join(&**fixtures));
+            list_repos(config, &forge, filters).await
+        }
+        None => list_repos(config, &GitHub::new(client, &config.dirs.github), filters).await,
+    }
+}
+
+async fn list_repos<F: Forge>(config: &Config, forge: &F, filters: &Filters) -> anyhow::Result<()> {
+    for org in &config.github.organizations {
+        let repos = forge
+            .organization_repos(&org.name)
+            .await
+            .with_context(|| format!("failed to fetch repos from organization {}", org.name))?;
 
+        for repo in &repos {
+            match fetcher::exclusion(org, filters, repo) {
+                Some(reason) => println!("{}/{} skipped: {}", org.name, repo.name, reason),
+                None => println!("{}/{} selected", org.name, repo.name),
+            }
+        }
+    }
+
+    let standalone = config
+        .github
+        .repos
+        .iter()
+        .map(|full_name| {
+            let name = full_name.rsplit('/').next().unwrap_or(&**full_name);
+            (&**full_name, name)
+        })
+        .chain(
+            config
+                .remotes
+                .iter()
+                .map(|remote| (&*remote.url, &*remote.name)),
+        );
+
+    for (source, name) in standalone {
+        if filters.repo(name) {
+            println!("{} selected", source);
+        } else {
+            println!("{} skipped: not accepted by --repo", source);
+        }
+    }
+
+    Ok(())
+}
+
+fn set_credentials(config: &Config) -> anyhow::Result<()> {
+    let credentials = Credentials::from_config(&config.github.auth)
+        .context("failed to load GitHub credentials")?;
+    auth::set(credentials);
+    Ok(())
+}
+
+/// Registers the secrets of the config, where they can be loaded, so that recorded reasons
+/// are printed without them.
+fn register_secrets(config: &Config) {
+    if let Ok(credentials) = Credentials::from_config(&config.github.auth) {
+        auth::set(credentials);
+    }
+
+    if let Ok(secret) = std::fs::read_to_string(&*config.webhook.secret_file) {
+        auth::register_secret(Secret::Webhook, secret.trim());
+    }
+}
+
+/// Prints a line of output, without any known secrets.
+fn print_redacted(line: &str) {
+    println!("{}", auth::redact(line));
+}
+
+async fn run(
+    config: Arc<Config>,
+    filters: &Filters,
+    stage: Stage,
+    selection: Selection<'_>,
+) -> anyhow::Result<()> {
+    let client = Arc::new(Client::new());
+    set_credentials(&config)?;
+
+    match config.fixtures.as_ref() {
+        Some(fixtures) => {
+            let forge = Fixture::new(config.dirs.base.join(&**fixtures));
+            pipeline(config.clone(), client, &forge, filters, stage, selection).await
+        }
+        None => {
+            let forge = GitHub::new(client.clone(), &config.dirs.github);
+            pipeline(config.clone(), client, &forge, filters, stage, selection).await
+        }
+    }
+}
+
+async fn pipeline<F: Forge>(
+    config: Arc<Config>,
+    client: Arc<Client>,
+    forge: &F,
+    filters: &Filters,
+    stage: Stage,
+    selection: Selection<'_>,
+) -> anyhow::Result<()> {
     env::set_var("QUILT_PATCHES", "debian/patches");
 
-    let blacklist_buffer = &mut String::new();
-    let (mut blacklist_file, blacklisted) = startup(&config, blacklist_buffer).await?;
-    let blacklisted: &[(&str, &str)] = &blacklisted;
+    let state = &startup(&config, stage).await?;
 
-    let fetcher = Fetcher::new(&client, &config);
+    let fetcher = Fetcher::new(forge, &config, filters, state);
 
-    let (mut blacklist_tx, mut blacklist_rx) = unbounded_channel();
+    let (pool_tx, mut pool_rx) = unbounded_channel();
 
     let fetcher = async {
+        // Owned by this future, so that the channel closes when fetching is done.
+        let pool_tx = pool_tx;
+
+        let process = |result: Result<Repository, fetcher::Error>| {
+            let config = config.clone();
+            let client = client.clone();
+            let mut pool_tx = pool_tx.clone();
+
+            async move {
+                // Nothing is removed from the apt repositories when a repository fails to fetch.
+                let repo = match result {
+                    Ok(repo) => repo,
+                    Err(why) => {
+                        format_error(&why, |why| error!("fetching error: {}", why));
+                        return;
+                    }
+                };
+
+                let package = repo.name.clone();
+                let targets = match selection {
+                    Selection::Withdrawal { .. } => {
+                        let targets = collate::targets(&config, filters, &repo);
+                        let _ = pool_tx
+                            .send(Publication::Targets { package, targets })
+                            .await;
+                        return;
+                    }
+                    Selection::All if stage == Stage::Publish => {
+                        Some(collate::targets(&config, filters, &repo))
+                    }
+                    Selection::All | Selection::Repository { .. } => None,
+                };
+
+                process_repo(
+                    &config,
+                    &client,
+                    forge,
+                    filters,
+                    stage,
+                    repo,
+                    state,
+                    pool_tx.clone(),
+                )
+                .await;
+
+                // Sent after the packages which were built, which are among the targets.
+                if let Some(targets) = targets {
+                    let _ = pool_tx
+                        .send(Publication::Targets { package, targets })
+                        .await;
+                }
+            }
+        };
+
+        if let Selection::Repository { owner, name } | Selection::Withdrawal { owner, name, .. } =
+            selection
+        {
+            process(fetcher.repository(owner, name).await).await;
+
+            drop(process);
+            drop(pool_tx);
+            return;
+        }
+
         for organization in &config.github.organizations {
             info!("fetching github organization: {}", organization.name);
             let repos = match fetcher.organization(&organization.name).await {
@@ -75,97 +691,198 @@ async fn main_() -> Result<(), anyhow::Error> {
 
             fetcher
                 .repos(&organization, &repos)
-                .for_each_concurrent(config.concurrent_builds, |result| {
-                    let config = config.clone();
-                    let client = client.clone();
-                    let blacklist_tx = blacklist_tx.clone();
-
-                    async move {
-                        let repo = match result {
-                            Ok(repo) => repo,
-                            Err(why) => {
-                                format_error(&why, |why| error!("fetching error: {}", why));
-                                return;
-                            }
-                        };
-
-                        process_repo(
-                            &config,
-                            &client,
-                            organization,
-                            repo,
-                            blacklisted,
-                            blacklist_tx,
-                        )
-                        .await;
-                    }
-                })
+                .for_each_concurrent(config.concurrent_builds, &process)
                 .await;
         }
+
+        fetcher
+            .standalone()
+            .for_each_concurrent(config.concurrent_builds, &process)
+            .await;
+
+        // Closes the channel, so that its receiver knows when to stop.
+        drop(process);
+        drop(pool_tx);
     };
 
-    let mut buffer = String::new();
+    fetcher.await;
 
-    let blacklist_writer = async move {
-        while let Some((git_id, series)) = blacklist_rx.next().await {
-            warn!("appending {} ({}) to blacklist", git_id, series);
+    let mut dists = HashSet::new();
 
-            buffer.clear();
-            buffer.push_str(&git_id);
-            buffer.push(' ');
-            buffer.push_str(&series);
-            buffer.push('\n');
+    while let Some(publication) = pool_rx.next().await {
+        match publication {
+            Publication::Pool(entry) => {
+                let PoolEntry {
+                    pocket,
+                    codename,
+                    package,
+                    files,
+                } = entry;
 
-            if let Err(why) = blacklist_file.write_all(buffer.as_bytes()).await {
-                error!("failed to write {} to blacklist", git_id);
+                info!("{} on {}: adding {} to the pool", pocket, codename, package);
+                if let Err(why) = apt::pool(&config, &pocket, &codename, &package, &files).await {
+                    format_error(&why, |why| {
+                        error!(
+                            "{} on {}: failed to pool {}: {}",
+                            pocket, codename, package, why
+                        )
+                    });
+                    continue;
+                }
+
+                dists.insert((pocket, codename));
+            }
+            Publication::Targets { package, targets } => {
+                let removed = apt::unpool(&config, &package, |pocket, codename| {
+                    let target: (Box<str>, Box<str>) = (pocket.into(), codename.into());
+                    selection.prunes(filters, pocket, codename) && !targets.contains(&target)
+                })
+                .await;
+
+                match removed {
+                    Ok(removed) => {
+                        for (pocket, codename) in removed {
+                            info!(
+                                "{} on {}: removed {} from the pool",
+                                pocket, codename, package
+                            );
+                            dists.insert((pocket, codename));
+                        }
+                    }
+                    Err(why) => format_error(&why, |why| {
+                        error!("failed to remove {} from the pool: {}", package, why)
+                    }),
+                }
             }
         }
-    };
+    }
+
+    if stage == Stage::Publish {
+        // Pockets of closed pull requests and deleted branches are left without packages.
+        let removed = apt::remove_empty_pockets(&config)
+            .await
+            .context("failed to remove empty pockets")?;
+
+        for pocket in &removed {
+            info!("{}: removing pocket without packages", pocket);
+        }
+
+        dists.retain(|(pocket, _)| !removed.contains(pocket));
+    }
 
-    // Runs the fetcher and blacklist writer at the same time.
-    join!(fetcher, blacklist_writer);
+    for (pocket, codename) in dists {
+        let version = match config.series.get(&codename) {
+            Some(series) => &series.release,
+            None => continue,
+        };
+
+        info!("{} on {}: generating dist", pocket, codename);
+        if let Err(why) = apt::create_dist(&config, &pocket, &codename, version).await {
+            format_error(&why, |why| {
+                error!(
+                    "{} on {}: failed to generate dist: {}",
+                    pocket, codename, why
+                )
+            });
+        }
+    }
 
     Ok(())
 }
 
-async fn process_repo(
+async fn process_repo<F: Forge>(
     config: &Config,
     client: &Arc<Client>,
-    org: &ConfigOrganization,
+    forge: &F,
+    filters: &Filters,
+    stage: Stage,
     repo: Repository,
-    blacklisted: &[(&str, &str)],
-    mut blacklist: UnboundedSender<(Box<str>, Box<str>)>,
+    state: &State,
+    mut pool: UnboundedSender<Publication>,
 ) -> Result<(), Error> {
-    let build_queue = collate::build_queue(&config, &repo).await;
+    if stage == Stage::Fetch {
+        for branch in repo.branches.iter() {
+            info!(
+                "{} branch {}: at commit {}",
+                repo.name, branch.name, branch.sha
+            );
+        }
 
-    let mut deb_paths = Vec::new();
+        return Ok(());
+    }
+
+    let build_queue = collate::build_queue(&config, client, state, filters, &repo).await;
+
+    if stage == Stage::Plan {
+        for (series, pockets) in &build_queue {
+            for (pocket, build) in pockets {
+                print_redacted(&format!(
+                    "{} {} {} {}",
+                    repo.name, series, pocket, build.git.id
+                ));
+            }
+        }
+
+        return Ok(());
+    }
+
+    let overrides = config.repo(&repo.name);
 
     for (series, pockets) in &build_queue {
         let release = &config.series[*series];
-        for (pocket, git_tar) in pockets {
-            if blacklisted.contains(&(&*git_tar.id, pocket)) {
-                info!(
-                    "{} commit {} on {}: skipping because it is blacklisted",
-                    repo.name, git_tar.id, *series
-                );
+        for (pocket, build) in pockets {
+            let git_tar = &build.git;
+
+            match state.is_blacklisted(&repo.name, &git_tar.id, series, "source") {
+                Ok(true) => {
+                    info!(
+                        "{} commit {} on {}: skipping because it is blacklisted",
+                        repo.name, git_tar.id, *series
+                    );
+                    continue;
+                }
+                Ok(false) => (),
+                Err(why) => {
+                    format_error(&why, |why| {
+                        error!(
+                            "{} commit {} on {}: failed to check blacklist: {}",
+                            repo.name, git_tar.id, *series, why
+                        )
+                    });
+                }
             }
 
             let dpkg = dpkg::Dpkg {
                 config: &config,
                 client: &client,
+                forge,
+                state,
+                owner: repo.owner.as_ref().map(|owner| owner.as_ref()),
                 repo: &repo,
                 codename: *series,
+                pocket,
                 release: release,
                 git: git_tar,
+                tagged: build.release,
             };
 
             // Generate the source tarballs and dsc files
             match dpkg.source().await {
                 Ok((dsc_path, tar_path, path_version)) => {
+                    if stage == Stage::Source {
+                        continue;
+                    }
+
+                    let mut deb_paths = Vec::new();
+
                     info!("building {}", dsc_path.display());
 
                     // For each supported arch, build debian packages from the source tarballs.
                     for (arch, &build_all) in &config.archs {
+                        if !filters.arch(arch) || !overrides.arch(arch) {
+                            continue;
+                        }
+
                         info!("building {} for {}", dsc_path.display(), arch);
                         match dpkg
                             .binary(&path_version, &dsc_path, &*arch, build_all)
@@ -180,15 +897,26 @@ async fn process_repo(
                             }
                         }
                     }
+
+                    if stage == Stage::Publish {
+                        let mut files = vec![dsc_path.into(), tar_path.into()];
+                        files.extend(deb_paths);
+
+                        let entry = PoolEntry {
+                            pocket: pocket.clone(),
+                            codename: Box::from(*series),
+                            package: repo.name.clone(),
+                            files,
+                        };
+
+                        let _ = pool.send(Publication::Pool(entry)).await;
+                    }
                 }
                 Err(why) => {
                     error!(
                         "{} commit {} on {}: {}",
                         repo.name, git_tar.id, *series, why
                     );
-                    let _ = blacklist
-                        .send((git_tar.id.clone(), Box::from(*series)))
-                        .await;
                 }
             }
         }
@@ -208,31 +936,15 @@ async fn main() {
     }
 }
 
-fn format_error<F: FnOnce(&str)>(why: &(dyn StdError + 'static), func: F) {
-    STRING_BUF.with(|buffer| {
-        let mut buffer = buffer.borrow_mut();
-
-        buffer.clear();
-        let _ = writeln!(buffer, "{}", why);
-
-        let mut cause = why.source();
-        while let Some(error) = cause {
-            let _ = writeln!(buffer, "    caused by: {}", error);
-            cause = error.source();
-        }
-
-        func(&buffer);
-    });
-}
-
 fn setup_logger() -> Result<(), fern::InitError> {
     fern::Dispatch::new()
         .format(|out, message, record| {
+            // Secrets must never reach the terminal or the log file.
             out.finish(format_args!(
                 "{} [{}] {}",
                 chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
                 record.level(),
-                message
+                auth::redact(&message.to_string())
             ))
         })
         .level(log::LevelFilter::Warn)
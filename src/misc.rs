@@ -7,15 +7,26 @@ use std::{
     os::unix::process::ExitStatusExt,
     path::Path,
     process::{ExitStatus, Stdio},
+    time::Duration,
 };
 
-use tokio::net::process::Command;
+use tokio::{net::process::Command, timer::Timeout};
 
 /// Asynchronously execute a command and wait for its exit status.
 pub async fn check_call<'a, S: AsRef<OsStr>>(
     cmd: &'a str,
     args: &'a [S],
     cwd: Option<&'a Path>,
+) -> io::Result<()> {
+    check_call_timeout(cmd, args, cwd, None).await
+}
+
+/// Asynchronously execute a command, killing it if it does not exit within the timeout.
+pub async fn check_call_timeout<'a, S: AsRef<OsStr>>(
+    cmd: &'a str,
+    args: &'a [S],
+    cwd: Option<&'a Path>,
+    timeout: Option<Duration>,
 ) -> io::Result<()> {
     let mut command = Command::new(cmd);
 
@@ -23,11 +34,45 @@ pub async fn check_call<'a, S: AsRef<OsStr>>(
         command.current_dir(cwd);
     }
 
-    let status = dbg!(command.args(args)).status().await?;
+    let status = command.args(args).status();
+
+    // Dropping the future of a child process kills the child process.
+    let status = match timeout {
+        Some(timeout) => Timeout::new(status, timeout).await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} timed out after {} seconds", cmd, timeout.as_secs()),
+            )
+        })??,
+        None => status.await?,
+    };
 
     eval_status(cmd, status)
 }
 
+/// Asynchronously execute a command, capturing its output to explain why it failed.
+///
+/// The error of a failed command contains its stdout, followed by its stderr.
+pub async fn check_call_captured<'a, S: AsRef<OsStr>>(
+    cmd: &'a str,
+    args: &'a [S],
+    cwd: Option<&'a Path>,
+) -> io::Result<()> {
+    let mut command = Command::new(cmd);
+
+    if let Some(cwd) = cwd {
+        command.current_dir(cwd);
+    }
+
+    let output = command.args(args).output().await?;
+
+    eval_status(cmd, output.status).map_err(|why| {
+        let mut log = String::from_utf8_lossy(&output.stdout).into_owned();
+        log.push_str(&String::from_utf8_lossy(&output.stderr));
+        io::Error::new(why.kind(), format!("{}:\n{}", why, log.trim_end()))
+    })
+}
+
 /// Asynchronously fetch the UTF-8 stdout output of a command.
 pub async fn check_output<'a>(
     cmd: &'a str,
@@ -0,0 +1,163 @@
+//! Commits which are not to be built for a series, or for an arch of a series.
+//!
+//! An entry with the `source` arch blacklists the commit for every stage of that series.
+//! Entries may have an expiry time, after which they are ignored, and purged by `expire`.
+
+use super::{Error, State};
+use crate::filter::Filters;
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, OptionalExtension};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS blacklist (
+        repo TEXT NOT NULL,
+        commit_id TEXT NOT NULL,
+        series TEXT NOT NULL,
+        arch TEXT NOT NULL,
+        reason TEXT,
+        added INTEGER NOT NULL,
+        expires INTEGER,
+        PRIMARY KEY (repo, commit_id, series, arch)
+    );
+";
+
+#[derive(Debug, Clone)]
+pub struct BlacklistEntry {
+    pub repo: Box<str>,
+    pub commit: Box<str>,
+    pub series: Box<str>,
+    pub arch: Box<str>,
+    pub reason: Option<Box<str>>,
+    pub added: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl State {
+    /// Blacklists a commit for the arch of a series, replacing any existing entry.
+    pub fn blacklist_add(
+        &self,
+        repo: &str,
+        commit: &str,
+        series: &str,
+        arch: &str,
+        reason: Option<&str>,
+        expires: Option<DateTime<Utc>>,
+    ) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO blacklist
+                (repo, commit_id, series, arch, reason, added, expires)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                repo,
+                commit,
+                series,
+                arch,
+                reason,
+                Utc::now().timestamp(),
+                expires.map(|time| time.timestamp())
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Whether a commit has an unexpired blacklist entry for the arch of a series.
+    ///
+    /// Commits which are blacklisted for the `source` arch are blacklisted for every arch.
+    pub fn is_blacklisted(
+        &self,
+        repo: &str,
+        commit: &str,
+        series: &str,
+        arch: &str,
+    ) -> Result<bool, Error> {
+        let found = self
+            .conn()
+            .query_row(
+                "SELECT 1 FROM blacklist
+                    WHERE repo = ?1 AND commit_id = ?2 AND series = ?3
+                    AND (arch = ?4 OR arch = 'source')
+                    AND (expires IS NULL OR expires > ?5)
+                    LIMIT 1",
+                params![repo, commit, series, arch, Utc::now().timestamp()],
+                |_| Ok(()),
+            )
+            .optional()?;
+
+        Ok(found.is_some())
+    }
+
+    /// Removes entries of a commit, optionally limited to a series and an arch.
+    ///
+    /// Commits may be given as an abbreviated hash.
+    pub fn blacklist_remove(
+        &self,
+        repo: &str,
+        commit: &str,
+        series: Option<&str>,
+        arch: Option<&str>,
+    ) -> Result<usize, Error> {
+        let removed = self.conn().execute(
+            "DELETE FROM blacklist
+                WHERE repo = ?1 AND commit_id LIKE ?2 || '%'
+                AND (?3 IS NULL OR series = ?3)
+                AND (?4 IS NULL OR arch = ?4)",
+            params![repo, commit, series, arch],
+        )?;
+
+        Ok(removed)
+    }
+
+    /// Purges entries which have expired.
+    pub fn blacklist_expire(&self) -> Result<usize, Error> {
+        let expired = self.conn().execute(
+            "DELETE FROM blacklist WHERE expires IS NOT NULL AND expires <= ?1",
+            params![Utc::now().timestamp()],
+        )?;
+
+        Ok(expired)
+    }
+
+    /// Removes every entry.
+    pub fn blacklist_clear(&self) -> Result<usize, Error> {
+        Ok(self.conn().execute("DELETE FROM blacklist", params![])?)
+    }
+
+    /// Fetches every entry which is accepted by the filters, including expired entries.
+    pub fn blacklist(&self, filters: &Filters) -> Result<Vec<BlacklistEntry>, Error> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT repo, commit_id, series, arch, reason, added, expires
+                FROM blacklist ORDER BY repo, series, commit_id, arch",
+        )?;
+
+        let rows = statement.query_map(params![], |row| {
+            Ok(BlacklistEntry {
+                repo: row.get::<_, String>(0)?.into(),
+                commit: row.get::<_, String>(1)?.into(),
+                series: row.get::<_, String>(2)?.into(),
+                arch: row.get::<_, String>(3)?.into(),
+                reason: row.get::<_, Option<String>>(4)?.map(Box::from),
+                added: Utc.timestamp(row.get(5)?, 0),
+                expires: row
+                    .get::<_, Option<i64>>(6)?
+                    .map(|secs| Utc.timestamp(secs, 0)),
+            })
+        })?;
+
+        let mut entries = Vec::new();
+        for entry in rows {
+            let entry = entry?;
+
+            let accepted = filters.repo(&entry.repo)
+                && filters.series(&entry.series)
+                && (&*entry.arch == "source" || filters.arch(&entry.arch));
+
+            if accepted {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,360 @@
+//! Persistent record of every build stage, stored in an embedded SQLite database.
+//!
+//! Each stage of a commit is keyed by its repo, commit, series, pocket, and arch. Source
+//! stages use `source` as their arch. Writes are committed as they happen, so the state of
+//! a run survives crashes, and may be queried with `pop-ci status` or `sqlite3`.
+
+mod blacklist;
+mod repos;
+
+pub use self::blacklist::BlacklistEntry;
+
+use crate::filter::Filters;
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to open the state database at {}", _0.display())]
+    Open(Box<Path>, #[source] rusqlite::Error),
+    #[error("the state database contains invalid branches of {}", _0)]
+    Branches(Box<str>, #[source] serde_json::Error),
+    #[error("failed to query the state database")]
+    Query(#[from] rusqlite::Error),
+    #[error("the state database contains an unknown {} value: {}", _0, _1)]
+    Unknown(&'static str, Box<str>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Source,
+    Binary,
+}
+
+impl Stage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Source => "source",
+            Stage::Binary => "binary",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, Error> {
+        match value {
+            "source" => Ok(Stage::Source),
+            "binary" => Ok(Stage::Binary),
+            _ => Err(Error::Unknown("stage", value.into())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The stage was started, and has not finished yet.
+    Building,
+    /// The stage was building when the previous run ended unexpectedly.
+    Interrupted,
+    Succeeded,
+    Failed,
+    /// The commit does not have a debian directory, so there is nothing to build.
+    NoDebian,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Building => "building",
+            Status::Interrupted => "interrupted",
+            Status::Succeeded => "succeeded",
+            Status::Failed => "failed",
+            Status::NoDebian => "no-debian",
+        }
+    }
+
+    fn parse(value: &str) -> Result<Self, Error> {
+        match value {
+            "building" => Ok(Status::Building),
+            "interrupted" => Ok(Status::Interrupted),
+            "succeeded" => Ok(Status::Succeeded),
+            "failed" => Ok(Status::Failed),
+            "no-debian" => Ok(Status::NoDebian),
+            _ => Err(Error::Unknown("status", value.into())),
+        }
+    }
+}
+
+/// Identifies a stage of a commit's build for a series, pocket, and arch.
+#[derive(Debug, Clone, Copy)]
+pub struct BuildKey<'a> {
+    pub repo: &'a str,
+    pub commit: &'a str,
+    pub series: &'a str,
+    pub pocket: &'a str,
+    pub arch: &'a str,
+    pub stage: Stage,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildRecord {
+    pub repo: Box<str>,
+    pub commit: Box<str>,
+    pub series: Box<str>,
+    pub pocket: Box<str>,
+    pub arch: Box<str>,
+    pub stage: Stage,
+    pub status: Status,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    pub reason: Option<Box<str>>,
+    pub artifacts: Vec<PathBuf>,
+}
+
+pub struct State {
+    conn: Mutex<Connection>,
+}
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = FULL;
+
+    CREATE TABLE IF NOT EXISTS builds (
+        repo TEXT NOT NULL,
+        commit_id TEXT NOT NULL,
+        series TEXT NOT NULL,
+        pocket TEXT NOT NULL,
+        arch TEXT NOT NULL,
+        stage TEXT NOT NULL,
+        status TEXT NOT NULL,
+        started INTEGER,
+        finished INTEGER,
+        reason TEXT,
+        artifacts TEXT,
+        PRIMARY KEY (repo, commit_id, series, pocket, arch, stage)
+    );
+
+    CREATE TABLE IF NOT EXISTS commits (
+        repo TEXT NOT NULL,
+        commit_id TEXT NOT NULL,
+        has_debian INTEGER NOT NULL,
+        PRIMARY KEY (repo, commit_id)
+    );
+";
+
+const KEY_CLAUSE: &str = "repo = ?1 AND commit_id = ?2 AND series = ?3 \
+                          AND pocket = ?4 AND arch = ?5 AND stage = ?6";
+
+impl State {
+    /// Opens the database, creating it if it does not exist.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let conn = Connection::open(path).map_err(|why| Error::Open(path.into(), why))?;
+
+        conn.execute_batch(SCHEMA)
+            .and_then(|_| conn.execute_batch(blacklist::SCHEMA))
+            .and_then(|_| conn.execute_batch(repos::SCHEMA))
+            .map_err(|why| Error::Open(path.into(), why))?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Marks the stages which were still building when a previous run ended as interrupted,
+    /// returning how many there were.
+    pub fn interrupt(&self) -> Result<usize, Error> {
+        let interrupted = self.conn().execute(
+            "UPDATE builds SET status = ?1 WHERE status = ?2",
+            params![Status::Interrupted.as_str(), Status::Building.as_str()],
+        )?;
+
+        Ok(interrupted)
+    }
+
+    /// Records that a stage has started, replacing the outcome of any previous attempt.
+    pub fn started(&self, key: &BuildKey) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO builds
+                (repo, commit_id, series, pocket, arch, stage, status, started)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                key.repo,
+                key.commit,
+                key.series,
+                key.pocket,
+                key.arch,
+                key.stage.as_str(),
+                Status::Building.as_str(),
+                Utc::now().timestamp()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records the outcome of a stage, along with the reason for failures.
+    pub fn finished(
+        &self,
+        key: &BuildKey,
+        status: Status,
+        reason: Option<&str>,
+        artifacts: &[Box<Path>],
+    ) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT INTO builds
+                (repo, commit_id, series, pocket, arch, stage, status, finished, reason, artifacts)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                ON CONFLICT (repo, commit_id, series, pocket, arch, stage) DO UPDATE SET
+                    status = excluded.status,
+                    finished = excluded.finished,
+                    reason = excluded.reason,
+                    artifacts = excluded.artifacts",
+            params![
+                key.repo,
+                key.commit,
+                key.series,
+                key.pocket,
+                key.arch,
+                key.stage.as_str(),
+                status.as_str(),
+                Utc::now().timestamp(),
+                reason,
+                join_artifacts(artifacts)
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records artifacts which were built by a stage, if that stage has no record yet.
+    pub fn existing(&self, key: &BuildKey, artifacts: &[Box<Path>]) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO builds
+                (repo, commit_id, series, pocket, arch, stage, status, artifacts)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                key.repo,
+                key.commit,
+                key.series,
+                key.pocket,
+                key.arch,
+                key.stage.as_str(),
+                Status::Succeeded.as_str(),
+                join_artifacts(artifacts)
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// The status of a stage, if it was ever attempted.
+    pub fn status(&self, key: &BuildKey) -> Result<Option<Status>, Error> {
+        let status = self
+            .conn()
+            .query_row(
+                &["SELECT status FROM builds WHERE ", KEY_CLAUSE].concat(),
+                params![
+                    key.repo,
+                    key.commit,
+                    key.series,
+                    key.pocket,
+                    key.arch,
+                    key.stage.as_str()
+                ],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        status.map(|status| Status::parse(&status)).transpose()
+    }
+
+    /// Whether a commit has a debian directory, if it was checked before.
+    pub fn has_debian(&self, repo: &str, commit: &str) -> Result<Option<bool>, Error> {
+        let has_debian = self
+            .conn()
+            .query_row(
+                "SELECT has_debian FROM commits WHERE repo = ?1 AND commit_id = ?2",
+                params![repo, commit],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(has_debian)
+    }
+
+    /// Remembers whether a commit has a debian directory.
+    pub fn set_has_debian(&self, repo: &str, commit: &str, has_debian: bool) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO commits (repo, commit_id, has_debian) VALUES (?1, ?2, ?3)",
+            params![repo, commit, has_debian],
+        )?;
+
+        Ok(())
+    }
+
+    /// Fetches every record which is accepted by the filters.
+    pub fn query(&self, filters: &Filters) -> Result<Vec<BuildRecord>, Error> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT repo, commit_id, series, pocket, arch, stage, status,
+                started, finished, reason, artifacts
+                FROM builds ORDER BY repo, series, pocket, commit_id, stage, arch",
+        )?;
+
+        let mut records = Vec::new();
+        for record in statement.query_map(params![], |row| Ok(record_from_row(row)))? {
+            let record = record??;
+
+            let accepted = filters.repo(&record.repo)
+                && filters.series(&record.series)
+                && filters.pocket(&record.pocket)
+                && (record.stage == Stage::Source || filters.arch(&record.arch));
+
+            if accepted {
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn conn(&self) -> MutexGuard<Connection> {
+        self.conn.lock().expect("state database mutex was poisoned")
+    }
+}
+
+fn record_from_row(row: &Row) -> Result<BuildRecord, Error> {
+    let timestamp = |secs: Option<i64>| secs.map(|secs| Utc.timestamp(secs, 0));
+
+    Ok(BuildRecord {
+        repo: row.get::<_, String>(0)?.into(),
+        commit: row.get::<_, String>(1)?.into(),
+        series: row.get::<_, String>(2)?.into(),
+        pocket: row.get::<_, String>(3)?.into(),
+        arch: row.get::<_, String>(4)?.into(),
+        stage: Stage::parse(&row.get::<_, String>(5)?)?,
+        status: Status::parse(&row.get::<_, String>(6)?)?,
+        started: timestamp(row.get(7)?),
+        finished: timestamp(row.get(8)?),
+        reason: row.get::<_, Option<String>>(9)?.map(Box::from),
+        artifacts: row
+            .get::<_, Option<String>>(10)?
+            .map(|paths| paths.lines().map(PathBuf::from).collect())
+            .unwrap_or_default(),
+    })
+}
+
+fn join_artifacts(artifacts: &[Box<Path>]) -> Option<String> {
+    if artifacts.is_empty() {
+        return None;
+    }
+
+    let paths = artifacts
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>();
+
+    Some(paths.join("\n"))
+}
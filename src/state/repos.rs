@@ -0,0 +1,72 @@
+//! The branches of each repository as they were when it was last fetched.
+//!
+//! Repositories of the forge report when they were last pushed to. A repository which has not
+//! been pushed to since it was last fetched, with the same rules, has the same branches, so
+//! they are loaded from here instead of being fetched again.
+
+use super::{Error, State};
+use crate::fetcher::Branch;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension};
+
+pub(super) const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS repos (
+        owner TEXT NOT NULL,
+        repo TEXT NOT NULL,
+        pushed_at INTEGER NOT NULL,
+        rules TEXT NOT NULL,
+        branches TEXT NOT NULL,
+        PRIMARY KEY (owner, repo)
+    );
+";
+
+impl State {
+    /// The branches of a repository, if it was fetched with the same `rules` after it was
+    /// last pushed to at `pushed_at`.
+    pub fn unchanged_branches(
+        &self,
+        owner: &str,
+        repo: &str,
+        pushed_at: DateTime<Utc>,
+        rules: &str,
+    ) -> Result<Option<Vec<Branch>>, Error> {
+        let branches = self
+            .conn()
+            .query_row(
+                "SELECT branches FROM repos
+                    WHERE owner = ?1 AND repo = ?2 AND pushed_at = ?3 AND rules = ?4",
+                params![owner, repo, pushed_at.timestamp(), rules],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        branches
+            .map(|branches| {
+                serde_json::from_str(&branches)
+                    .map_err(|why| Error::Branches([owner, "/", repo].concat().into(), why))
+            })
+            .transpose()
+    }
+
+    /// Records the branches of a repository which were fetched after it was pushed to at
+    /// `pushed_at`, replacing those of a previous fetch.
+    pub fn set_branches(
+        &self,
+        owner: &str,
+        repo: &str,
+        pushed_at: DateTime<Utc>,
+        rules: &str,
+        branches: &[Branch],
+    ) -> Result<(), Error> {
+        let branches = serde_json::to_string(branches)
+            .map_err(|why| Error::Branches([owner, "/", repo].concat().into(), why))?;
+
+        self.conn().execute(
+            "INSERT OR REPLACE INTO repos (owner, repo, pushed_at, rules, branches)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![owner, repo, pushed_at.timestamp(), rules, branches],
+        )?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,394 @@
+//! Receives GitHub webhook deliveries, and queues the repositories that they affect.
+//!
+//! Deliveries must be signed with the shared secret of the webhook, which GitHub sends as an
+//! HMAC-SHA256 of the body in the `X-Hub-Signature-256` header. Unsigned or mis-signed
+//! deliveries are rejected before their body is parsed.
+
+use futures::prelude::*;
+use hmac::{Hmac, Mac};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use sha2::Sha256;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::mpsc::UnboundedSender;
+
+const SIGNATURE_PREFIX: &str = "sha256=";
+
+/// GitHub caps the payloads of deliveries at 25 MB, so larger bodies are not read.
+const MAX_BODY_LEN: usize = 25 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("webhook server failed")]
+    Server(#[from] hyper::Error),
+}
+
+/// A change to a branch of a repository.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    pub owner: Box<str>,
+    pub repo: Box<str>,
+    /// The branch that changed, or the base branch of a pull request.
+    pub branch: Box<str>,
+    /// The number of the pull request which changed.
+    pub pull_request: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    /// Commits were pushed to a branch.
+    Push,
+    /// A branch was created.
+    Create,
+    /// A branch was deleted.
+    Delete,
+    /// A pull request was opened, reopened, or updated.
+    PullRequest,
+    /// A pull request was closed or merged.
+    PullRequestClosed,
+}
+
+/// Actions of pull request events which change what is built for the pull request.
+const PULL_REQUEST_ACTIONS: &[&str] = &["opened", "reopened", "synchronize", "edited"];
+
+#[derive(Deserialize)]
+struct Payload {
+    #[serde(default, rename = "ref")]
+    reference: Box<str>,
+    #[serde(default)]
+    ref_type: Option<Box<str>>,
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    action: Option<Box<str>>,
+    #[serde(default)]
+    pull_request: Option<PayloadPullRequest>,
+    repository: PayloadRepository,
+}
+
+#[derive(Deserialize)]
+struct PayloadPullRequest {
+    number: u64,
+    base: PayloadRef,
+}
+
+#[derive(Deserialize)]
+struct PayloadRef {
+    #[serde(rename = "ref")]
+    reference: Box<str>,
+}
+
+#[derive(Deserialize)]
+struct PayloadRepository {
+    full_name: Box<str>,
+}
+
+/// Serves the webhook endpoint, sending the events of verified deliveries to `events`.
+pub async fn serve(
+    address: SocketAddr,
+    secret: Box<str>,
+    events: UnboundedSender<Event>,
+) -> Result<(), Error> {
+    let secret: Arc<str> = Arc::from(secret);
+
+    let make_service = make_service_fn(move |_| {
+        let secret = secret.clone();
+        let events = events.clone();
+
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request| {
+                handle(secret.clone(), events.clone(), request)
+            }))
+        }
+    });
+
+    info!("listening for webhook deliveries on {}", address);
+    Server::bind(&address).serve(make_service).await?;
+
+    Ok(())
+}
+
+async fn handle(
+    secret: Arc<str>,
+    events: UnboundedSender<Event>,
+    request: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+
+    let signature = header("x-hub-signature-256");
+    let event_name = header("x-github-event");
+
+    let length = header("content-length").and_then(|length| length.parse::<usize>().ok());
+    if length.map_or(false, |length| length > MAX_BODY_LEN) {
+        warn!("rejected webhook delivery of {} bytes", length.unwrap_or(0));
+        return Ok(respond(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    // The length may be absent or wrong, so the cap is also applied while reading.
+    let mut body = Vec::with_capacity(length.unwrap_or(0));
+    let mut chunks = request.into_body();
+    while let Some(chunk) = chunks.try_next().await? {
+        if body.len() + chunk.len() > MAX_BODY_LEN {
+            warn!(
+                "rejected webhook delivery larger than {} bytes",
+                MAX_BODY_LEN
+            );
+            return Ok(respond(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    let verified = signature.map_or(false, |signature| verify(&secret, &body, &signature));
+    if !verified {
+        warn!("rejected webhook delivery with an invalid signature");
+        return Ok(respond(StatusCode::UNAUTHORIZED));
+    }
+
+    let event_name = match event_name {
+        Some(name) => name,
+        None => return Ok(respond(StatusCode::BAD_REQUEST)),
+    };
+
+    let event = match parse(&event_name, &body) {
+        Ok(Some(event)) => event,
+        Ok(None) => return Ok(respond(StatusCode::NO_CONTENT)),
+        Err(why) => {
+            warn!("failed to parse {} webhook delivery: {}", event_name, why);
+            return Ok(respond(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    info!(
+        "{}/{} branch {}: received {:?} event",
+        event.owner, event.repo, event.branch, event.kind
+    );
+
+    let mut events = events;
+    if events.send(event).await.is_err() {
+        return Ok(respond(StatusCode::SERVICE_UNAVAILABLE));
+    }
+
+    Ok(respond(StatusCode::ACCEPTED))
+}
+
+fn respond(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Checks the `sha256=<hex>` signature of a delivery, in constant time.
+pub fn verify(secret: &str, body: &[u8], signature: &str) -> bool {
+    if !signature.starts_with(SIGNATURE_PREFIX) {
+        return false;
+    }
+
+    let expected = match hex::decode(&signature[SIGNATURE_PREFIX.len()..]) {
+        Ok(expected) => expected,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_varkey(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+
+    mac.input(body);
+    mac.verify(&expected).is_ok()
+}
+
+/// Parses the branch event of a delivery, ignoring events which do not concern branches.
+pub fn parse(event_name: &str, body: &[u8]) -> Result<Option<Event>, serde_json::Error> {
+    let kind = match event_name {
+        "push" => EventKind::Push,
+        "create" => EventKind::Create,
+        "delete" => EventKind::Delete,
+        "pull_request" => EventKind::PullRequest,
+        _ => return Ok(None),
+    };
+
+    let payload = serde_json::from_slice::<Payload>(body)?;
+
+    let action = payload.action.as_ref().map_or("", |action| &**action);
+    let kind = match kind {
+        EventKind::PullRequest if action == "closed" => EventKind::PullRequestClosed,
+        EventKind::PullRequest if !PULL_REQUEST_ACTIONS.contains(&action) => return Ok(None),
+        kind => kind,
+    };
+
+    let is_branch = payload
+        .ref_type
+        .as_ref()
+        .map_or(false, |ref_type| &**ref_type == "branch");
+
+    let branch = match kind {
+        EventKind::Push if payload.reference.starts_with("refs/heads/") => {
+            &payload.reference["refs/heads/".len()..]
+        }
+        EventKind::Create | EventKind::Delete if is_branch => &payload.reference[..],
+        EventKind::PullRequest | EventKind::PullRequestClosed => {
+            match payload.pull_request.as_ref() {
+                Some(pull) => &pull.base.reference[..],
+                None => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    // Pushes which delete a branch are followed by a delete event.
+    if kind == EventKind::Push && payload.deleted {
+        return Ok(None);
+    }
+
+    let mut names = payload.repository.full_name.splitn(2, '/');
+    let (owner, repo) = match (names.next(), names.next()) {
+        (Some(owner), Some(repo)) => (owner, repo),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Event {
+        kind,
+        owner: owner.into(),
+        repo: repo.into(),
+        branch: branch.into(),
+        pull_request: payload.pull_request.as_ref().map(|pull| pull.number),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "It's a Secret to Everybody";
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.input(body);
+        [SIGNATURE_PREFIX, &hex::encode(mac.result().code())].concat()
+    }
+
+    fn payload(fields: &str) -> Vec<u8> {
+        [
+            "{",
+            fields,
+            r#""repository": { "full_name": "pop-os/hello" } }"#,
+        ]
+        .concat()
+        .into_bytes()
+    }
+
+    fn event(event_name: &str, fields: &str) -> Option<(EventKind, String, Option<u64>)> {
+        parse(event_name, &payload(fields)).unwrap().map(|event| {
+            assert_eq!(&*event.owner, "pop-os");
+            assert_eq!(&*event.repo, "hello");
+            (event.kind, event.branch.into(), event.pull_request)
+        })
+    }
+
+    fn pull_request(action: &str) -> Option<(EventKind, String, Option<u64>)> {
+        let fields = [
+            r#""action": ""#,
+            action,
+            r#"", "pull_request": { "number": 7, "base": { "ref": "master_eoan" } },"#,
+        ]
+        .concat();
+
+        event("pull_request", &fields)
+    }
+
+    #[test]
+    fn accepts_valid_signatures() {
+        let body = b"Hello, World!";
+        let signature = sign(SECRET, body);
+
+        assert!(verify(SECRET, body, &signature));
+        assert!(verify(
+            SECRET,
+            body,
+            &signature.to_uppercase().replace("SHA256=", "sha256=")
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        let body = b"Hello, World!";
+        let signature = sign(SECRET, body);
+
+        assert!(!verify(SECRET, b"Hello, World?", &signature));
+        assert!(!verify("another secret", body, &signature));
+        assert!(!verify(SECRET, body, ""));
+        assert!(!verify(SECRET, body, &signature[SIGNATURE_PREFIX.len()..]));
+        assert!(!verify(
+            SECRET,
+            body,
+            &signature.replace("sha256=", "sha1=")
+        ));
+        assert!(!verify(SECRET, body, "sha256=not hex"));
+        assert!(!verify(SECRET, body, &signature[..signature.len() - 2]));
+    }
+
+    #[test]
+    fn parses_pushes() {
+        assert_eq!(
+            event("push", r#""ref": "refs/heads/master_eoan","#),
+            Some((EventKind::Push, "master_eoan".into(), None))
+        );
+
+        assert_eq!(event("push", r#""ref": "refs/tags/1.0.0","#), None);
+        assert_eq!(
+            event("push", r#""ref": "refs/heads/master", "deleted": true,"#),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_branch_creation_and_deletion() {
+        let fields = r#""ref": "master_eoan", "ref_type": "branch","#;
+        assert_eq!(
+            event("create", fields),
+            Some((EventKind::Create, "master_eoan".into(), None))
+        );
+        assert_eq!(
+            event("delete", fields),
+            Some((EventKind::Delete, "master_eoan".into(), None))
+        );
+
+        let fields = r#""ref": "1.0.0", "ref_type": "tag","#;
+        assert_eq!(event("create", fields), None);
+        assert_eq!(event("delete", fields), None);
+    }
+
+    #[test]
+    fn parses_pull_requests() {
+        for &action in PULL_REQUEST_ACTIONS {
+            assert_eq!(
+                pull_request(action),
+                Some((EventKind::PullRequest, "master_eoan".into(), Some(7)))
+            );
+        }
+
+        assert_eq!(
+            pull_request("closed"),
+            Some((EventKind::PullRequestClosed, "master_eoan".into(), Some(7)))
+        );
+
+        assert_eq!(pull_request("labeled"), None);
+        assert_eq!(pull_request("assigned"), None);
+    }
+
+    #[test]
+    fn ignores_other_events() {
+        assert!(parse("issues", b"not json").unwrap().is_none());
+        assert!(parse("push", b"not json").is_err());
+    }
+}
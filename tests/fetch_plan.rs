@@ -0,0 +1,269 @@
+//! Runs the fetch and plan stages against a fixture forge and a local git remote.
+//!
+//! The fixtures are generated in a temporary directory, which becomes the working directory
+//! of the test, as the config is read from the working directory.
+
+use futures::prelude::*;
+use pop_ci::{
+    collate::{self, Build},
+    config::Config,
+    fetcher::{Fetcher, Repository},
+    filter::Filters,
+    forge::Fixture,
+    state::State,
+};
+use reqwest::Client;
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const CONFIG: &str = r#"
+email = "ci@example.com"
+fullname = "CI"
+context = "test"
+description = "Test"
+fixtures = "fixtures"
+concurrent_builds = 1
+extra_repositories = []
+
+[archs]
+amd64 = true
+
+[series]
+bionic = { release = "18.04", wildcard = true }
+eoan = { release = "19.10", wildcard = false }
+
+[github]
+skip_unchanged = false
+
+[[github.organizations]]
+name = "pop-os"
+
+[[remotes]]
+name = "remote"
+url = "{root}/remote"
+"#;
+
+const REPOS: &str = r#"[
+    { "name": "hello", "url": "https://github.com/pop-os/hello", "pushed_at": "2019-10-01T00:00:00Z" }
+]"#;
+
+fn git(cwd: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(cwd)
+        .env("GIT_AUTHOR_NAME", "CI")
+        .env("GIT_AUTHOR_EMAIL", "ci@example.com")
+        .env("GIT_COMMITTER_NAME", "CI")
+        .env("GIT_COMMITTER_EMAIL", "ci@example.com")
+        .output()
+        .expect("failed to run git");
+
+    assert!(
+        output.status.success(),
+        "git {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    String::from_utf8(output.stdout).unwrap().trim().to_owned()
+}
+
+/// Commits a file to the current branch, returning the commit.
+fn commit(cwd: &Path, path: &str, content: &str) -> String {
+    let file = cwd.join(path);
+    fs::create_dir_all(file.parent().unwrap()).unwrap();
+    fs::write(&file, content).unwrap();
+
+    git(cwd, &["add", "-A"]);
+    git(cwd, &["commit", "-q", "-m", path]);
+    git(cwd, &["rev-parse", "HEAD"])
+}
+
+fn init(dir: &Path) {
+    fs::create_dir_all(dir).unwrap();
+    git(dir, &["init", "-q"]);
+    git(dir, &["checkout", "-q", "-b", "master"]);
+}
+
+/// Sets up the fixtures, and makes their directory the working directory.
+fn fixtures() -> (PathBuf, [String; 4]) {
+    let root =
+        env::temp_dir().join(["pop-ci-fetch-plan-", &std::process::id().to_string()].concat());
+    if root.exists() {
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    let hello = root.join("fixtures/pop-os/hello");
+    init(&hello);
+    let changelog = commit(
+        &hello,
+        "debian/changelog",
+        "hello (1.0.0) eoan; urgency=medium\n",
+    );
+
+    // A gitlink which is not declared in `.gitmodules` is archived as an empty directory.
+    let gitlink = ["160000,", &changelog, ",vendor"].concat();
+    git(&hello, &["update-index", "--add", "--cacheinfo", &gitlink]);
+    git(&hello, &["commit", "-q", "-m", "vendor"]);
+    let master = git(&hello, &["rev-parse", "HEAD"]);
+
+    // Branches of the same commit share its archive.
+    git(&hello, &["branch", "master_eoan"]);
+
+    git(&hello, &["checkout", "-q", "-b", "proposed_eoan"]);
+    let proposed = commit(&hello, "README", "proposed\n");
+
+    // Branches with a `/` are not built by the default rules.
+    git(&hello, &["checkout", "-q", "-b", "feature/unbuilt"]);
+    commit(&hello, "README", "feature\n");
+
+    git(&hello, &["checkout", "-q", "--orphan", "nodebian"]);
+    git(&hello, &["rm", "-q", "-r", "-f", "."]);
+    let nodebian = commit(&hello, "README", "no debian directory\n");
+
+    let remote = root.join("remote");
+    init(&remote);
+    let remote_master = commit(
+        &remote,
+        "debian/changelog",
+        "remote (1.0.0) eoan; urgency=medium\n",
+    );
+
+    fs::write(root.join("fixtures/pop-os.json"), REPOS).unwrap();
+
+    let config = CONFIG.replace("{root}", &root.to_string_lossy());
+    fs::write(root.join("config.toml"), config).unwrap();
+
+    env::set_current_dir(&root).unwrap();
+
+    (root, [master, proposed, nodebian, remote_master])
+}
+
+fn plan(queue: &HashMap<&str, HashMap<Box<str>, Build>>) -> HashSet<(String, String, String)> {
+    queue
+        .iter()
+        .flat_map(|(series, pockets)| {
+            pockets.iter().map(move |(pocket, build)| {
+                (
+                    series.to_string(),
+                    pocket.to_string(),
+                    build.git.id.to_string(),
+                )
+            })
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn fetch_and_plan() {
+    let (root, [master, proposed, nodebian, remote_master]) = fixtures();
+
+    let config = Config::new().expect("failed to load config");
+    let forge = Fixture::new(config.dirs.base.join("fixtures"));
+    let state = State::open(&config.dirs.build.join("state.sqlite3")).unwrap();
+    let filters = Filters::default();
+    let client = Client::new();
+    let fetcher = Fetcher::new(&forge, &config, &filters, &state);
+
+    let organization = &config.github.organizations[0];
+    let repos = fetcher.organization(&organization.name).await.unwrap();
+
+    let mut fetched = fetcher
+        .repos(organization, &repos)
+        .chain(fetcher.standalone())
+        .map(|result| result.expect("failed to fetch"))
+        .collect::<Vec<Repository>>()
+        .await;
+
+    fetched.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(fetched.len(), 2);
+
+    let (hello, remote) = (&fetched[0], &fetched[1]);
+    assert_eq!(&*hello.name, "hello");
+    assert_eq!(hello.owner.as_ref().map(|owner| &**owner), Some("pop-os"));
+    assert_eq!(&*remote.name, "remote");
+    assert_eq!(remote.owner, None);
+
+    // Branches which are not built, or have no debian directory, are left out.
+    let mut branches = hello
+        .branches
+        .iter()
+        .map(|branch| (&*branch.name, &*branch.sha))
+        .collect::<Vec<_>>();
+    branches.sort();
+
+    assert_eq!(
+        branches,
+        vec![
+            ("master", &*master),
+            ("master_eoan", &*master),
+            ("proposed_eoan", &*proposed)
+        ]
+    );
+    assert!(!branches.iter().any(|&(_, sha)| sha == nodebian));
+
+    let queue = collate::build_queue(&config, &client, &state, &filters, hello).await;
+
+    let expected = [
+        ("bionic", "master", &master),
+        ("eoan", "master", &master),
+        ("eoan", "proposed", &proposed),
+    ]
+    .iter()
+    .map(|&(series, pocket, id)| (series.to_owned(), pocket.to_owned(), id.to_owned()))
+    .collect::<HashSet<_>>();
+
+    assert_eq!(plan(&queue), expected);
+
+    for pockets in queue.values() {
+        for build in pockets.values() {
+            assert!(build.git.archive.exists(), "git tar was not archived");
+        }
+    }
+
+    let master_tar = fs::File::open(config.dirs.git.join([&master, ".tar"].concat())).unwrap();
+    let vendored = tar::Archive::new(master_tar)
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().into_owned())
+        .filter(|path| path.starts_with("vendor"))
+        .collect::<Vec<_>>();
+
+    assert_eq!(vendored, vec![PathBuf::from("vendor")]);
+
+    let partials = fs::read_dir(&config.dirs.git)
+        .unwrap()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".partial"))
+        .count();
+
+    assert_eq!(partials, 0, "partial git tars were left behind");
+
+    let targets = collate::targets(&config, &filters, hello);
+    let expected_targets = [
+        ("master", "bionic"),
+        ("master", "eoan"),
+        ("proposed", "eoan"),
+    ]
+    .iter()
+    .map(|&(pocket, series)| (Box::from(pocket), Box::from(series)))
+    .collect::<HashSet<(Box<str>, Box<str>)>>();
+
+    assert_eq!(targets, expected_targets);
+
+    // The local remote only has a branch for the wildcard series.
+    let queue = collate::build_queue(&config, &client, &state, &filters, remote).await;
+    let expected = [("bionic", "master", &remote_master)]
+        .iter()
+        .map(|&(series, pocket, id)| (series.to_owned(), pocket.to_owned(), id.to_owned()))
+        .collect::<HashSet<_>>();
+
+    assert_eq!(plan(&queue), expected);
+
+    drop(state);
+    let _ = fs::remove_dir_all(root);
+}